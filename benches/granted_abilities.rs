@@ -0,0 +1,138 @@
+//! 10k entities each granted 16 abilities, the size of a busy scene full of NPCs.
+//! The baseline cases use the old layout, where every grant held a clone of its definition.
+//! Run with `cargo bench --bench granted_abilities`.
+
+use bevy::prelude::*;
+use bevy_abilities::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use smallvec::SmallVec;
+
+stats!(Stats {
+    Mana
+});
+
+const ENTITIES: usize = 10_000;
+const ABILITIES: usize = 16;
+
+fn setup() -> (Vec<TagId>, AbilityRegistry<Stats>) {
+    let mut tags = TagRegistry::new();
+    let mut registry = AbilityRegistry::<Stats>::new();
+    let ability_tags: Vec<TagId> = (0..ABILITIES)
+        .map(|i| tags.register(Box::leak(format!("Ability.Bench{i}").into_boxed_str())))
+        .collect();
+    for tag in ability_tags.iter() {
+        let definition = AbilityDefinition::<Stats>::new(*tag)
+            .with_stat_cost(StatCost::new(Stats::Mana, 10.))
+            .with_cooldown(2.);
+        registry.insert(*tag, definition);
+    }
+    (ability_tags, registry)
+}
+
+/// A grant in the old layout, with its own copy of the definition
+struct ClonedGrant {
+    definition: AbilityDefinition<Stats>,
+    level: u8,
+}
+
+#[derive(Component)]
+struct ClonedGrants(SmallVec<[ClonedGrant; 16]>);
+
+impl ClonedGrants {
+    fn from_tags(tags: impl IntoIterator<Item = TagId>, registry: &AbilityRegistry<Stats>) -> Self {
+        Self(tags.into_iter()
+            .filter_map(|t| registry.get(&t))
+            .map(|definition| ClonedGrant { definition: definition.clone(), level: 1 })
+            .collect())
+    }
+
+    fn level(&self, tag: TagId) -> Option<u8> {
+        self.0.iter().find(|g| g.definition.tags.ability == tag).map(|g| g.level)
+    }
+
+    fn ability(&self, tag: TagId) -> Option<Ability<Stats>> {
+        let granted = self.0.iter().find(|g| g.definition.tags.ability == tag)?;
+        Some(Ability::at_level(&granted.definition, granted.level))
+    }
+}
+
+fn cloned_definitions(c: &mut Criterion) {
+    let (tags, registry) = setup();
+
+    c.bench_function("baseline: grant 16 cloned definitions to 10k entities", |b| {
+        b.iter(|| {
+            let mut world = World::new();
+            world.spawn_batch((0..ENTITIES).map(|_| {
+                ClonedGrants::from_tags(tags.iter().copied(), &registry)
+            }));
+            black_box(world);
+        })
+    });
+
+    let granted: Vec<ClonedGrants> = (0..ENTITIES)
+        .map(|_| ClonedGrants::from_tags(tags.iter().copied(), &registry))
+        .collect();
+
+    c.bench_function("baseline: look up 16 cloned grants on 10k entities", |b| {
+        b.iter(|| {
+            for abilities in granted.iter() {
+                for tag in tags.iter() {
+                    black_box(abilities.level(*tag));
+                }
+            }
+        })
+    });
+
+    c.bench_function("baseline: build 16 abilities from cloned grants on 10k entities", |b| {
+        b.iter(|| {
+            for abilities in granted.iter() {
+                for tag in tags.iter() {
+                    black_box(abilities.ability(*tag));
+                }
+            }
+        })
+    });
+}
+
+fn granted_abilities(c: &mut Criterion) {
+    let (tags, registry) = setup();
+
+    c.bench_function("grant 16 abilities to 10k entities", |b| {
+        b.iter(|| {
+            let mut world = World::new();
+            world.spawn_batch((0..ENTITIES).map(|_| {
+                GrantedAbilities::<Stats>::innate(tags.iter().copied())
+            }));
+            black_box(world);
+        })
+    });
+
+    let granted: Vec<GrantedAbilities<Stats>> = (0..ENTITIES)
+        .map(|_| GrantedAbilities::innate(tags.iter().copied()))
+        .collect();
+
+    c.bench_function("look up 16 grants on 10k entities", |b| {
+        b.iter(|| {
+            for abilities in granted.iter() {
+                for tag in tags.iter() {
+                    black_box(abilities.level(*tag));
+                }
+            }
+        })
+    });
+
+    c.bench_function("build 16 abilities on 10k entities", |b| {
+        b.iter(|| {
+            for abilities in granted.iter() {
+                for tag in tags.iter() {
+                    black_box(abilities.ability(*tag, &registry));
+                }
+            }
+        })
+    });
+}
+
+criterion_group!(benches, cloned_definitions, granted_abilities);
+criterion_main!(benches);
@@ -52,25 +52,25 @@ struct MyAbilityTags {
 fn setup(
     mut commands: Commands,
     my_tags: Res<MyAbilityTags>,
-    abilities: Res<MyAbilityRegistry>,
 ) {
     let death_ability = my_tags.death;
     commands.spawn((
         ActiveTags::default(),
-        CurrentAbility::<Stats>::new(),
-        GrantedAbilities::<Stats>::from_tags([death_ability], &abilities),
+        ActiveAbilities::<Stats>::new(),
+        GrantedAbilities::<Stats>::innate([death_ability]),
     ));
 }
 
 fn trigger_die_ability(
     mut commands: Commands,
     my_tags: Res<MyAbilityTags>,
+    registry: Res<MyAbilityRegistry>,
     q: Query<(Entity, &GrantedAbilities<Stats>)>,
 ) {
     if let Ok((entity, abilities)) = q.single() {
         let death_tag = my_tags.death;
-        let death = abilities.get_from_tag(death_tag).unwrap();
-        commands.trigger(TryExecuteAbility { entity, ability: Ability::from(&death) });
+        let death = abilities.ability(death_tag, &registry).unwrap();
+        commands.trigger(TryExecuteAbility { entity, ability: death });
     }
 }
 
@@ -85,7 +85,7 @@ fn handle_death(
     let mut entity = commands.get_entity(entity).unwrap();
     //entity.remove::<GameplayStats<Stats>>();
     entity.remove::<GrantedAbilities<Stats>>();
-    entity.remove::<CurrentAbility<Stats>>();
+    entity.remove::<ActiveAbilities<Stats>>();
 
     // Play death animation, sound cues, etc.
 }
\ No newline at end of file
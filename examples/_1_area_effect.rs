@@ -1,6 +1,6 @@
 /// This example demonstrates a simple stun area effect.
 /// It also shows how you can use tags to implement mechaincs
-/// like blocking character movement or ability cooldowns.
+/// like blocking character movement, and a built in ability cooldown.
 /// 
 /// Use WASD to move and space bar to execute the ability.
 /// It costs 25 mana and you start with 100, so you can do it 4 times.
@@ -18,9 +18,6 @@ use shared::{SharedPlugin, MoveTarget, Player, Stats, Enemy};
 const MOVE_SPEED: f32 = 2.;
 
 
-#[derive(Component, Clone, Deref, DerefMut)]
-struct PreStunCue(Timer);
-
 #[derive(Clone)]
 struct StunTrigger;
 
@@ -30,10 +27,8 @@ struct EnemyStunShake;
 #[derive(Resource)]
 struct StunTags {
     character_movement_blocked_stunned: TagId,
-    character_movement_blocked_casting: TagId,
     character_movement_blocked: TagId,
     ability_stun: TagId,
-    ability_stun_cooldown: TagId,
 }
 
 fn main() {
@@ -50,8 +45,8 @@ fn main() {
     let ability_stun_cooldown = tag_registry.register("Ability.Stun.Cooldown");
 
     let tags = StunTags{
-        ability_stun, ability_stun_cooldown, character_movement_blocked_stunned,
-        character_movement_blocked, character_movement_blocked_casting
+        ability_stun, character_movement_blocked_stunned,
+        character_movement_blocked,
     };
     app.insert_resource(tag_registry);
     app.insert_resource(tags);
@@ -60,18 +55,25 @@ fn main() {
      | Build stun ability |
      +--------------------*/
     let stun_tree = tree!{
-        Behave::Sequence => {
-            // Do a little animation cue
-            Behave::spawn(PreStunCue(Timer::from_seconds(1., TimerMode::Once))),
-            // Trigger the effect on enemies
-            Behave::trigger(StunTrigger)
-        }
+        // Trigger the effect on enemies once the cast completes
+        Behave::trigger(StunTrigger)
     };
     let stun_abililty = AbilityDefinition::<Stats>::new(ability_stun)
-        .adds_tags([character_movement_blocked_casting])
-        .blocked_by([ability_stun_cooldown, character_movement_blocked_casting])
+        // Wind up for 1 second before the tree runs.
+        // Movement is blocked by the casting tag only while casting.
+        .with_cast_time(
+            AbilityCastTime::new(1.)
+                .with_tags([character_movement_blocked_casting])
+        )
+        .blocked_by([character_movement_blocked_casting])
+        // Prevent re-casting for 5 seconds.  The tag is optional, it just
+        // lets other systems see that the stun is cooling down.
+        .with_cooldown(5.)
+        .with_cooldown_tag(ability_stun_cooldown)
         .with_execution_tree(stun_tree)
-        .with_stat_cost(StatCost::<Stats>{ stat: Stats::Mana, amount: 25. });
+        .with_stat_cost(StatCost::<Stats>::new(Stats::Mana, 25.))
+        // Let the plugin pay the mana cost when the ability starts
+        .with_cost_policy(CostPolicy::OnActivate);
 
         
     /*-----------------------+
@@ -98,7 +100,7 @@ fn main() {
             move_enemies_towards_targets,
             player_movement,
             execute_stun_ability,
-            pre_stun_cue,
+            stun_cast_cue,
             enemy_stun_shake,
         ))
         .add_observer(trigger_stun)
@@ -109,7 +111,6 @@ fn setup_player(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    abilities: Res<AbilityRegistry<Stats>>,
     tags: Res<StunTags>,
 ) {
     let capsule = meshes.add(Capsule3d::default().mesh());
@@ -123,10 +124,8 @@ fn setup_player(
         Player,
         ActiveTags::new(),
         ActiveEffects::<Stats>::new(None),
-        GrantedAbilities::<Stats>::from_tags(
-            [tags.ability_stun], &abilities
-        ),
-        CurrentAbility::<Stats>::default(),
+        GrantedAbilities::<Stats>::innate([tags.ability_stun]),
+        ActiveAbilities::<Stats>::default(),
         GameplayStats::<Stats>::new(
             |s| {
                 match s {
@@ -176,16 +175,14 @@ fn player_movement(
  | Ability Input |
  +---------------*/
 fn execute_stun_ability(
-    player: Query<(Entity, &GrantedAbilities<Stats>), With<Player>>,
+    player: Query<Entity, With<Player>>,
     input: Res<ButtonInput<KeyCode>>,
     tags: Res<StunTags>,
     mut commands: Commands,
 ) {
     if input.just_pressed(KeyCode::Space) {
-        let (entity, abilities) = player.single().unwrap();
-        let stun_ability_def = abilities.get_from_tag(tags.ability_stun).unwrap();
-        let ability = Ability::from(&stun_ability_def);
-        commands.trigger(TryExecuteAbility { entity, ability });
+        let entity = player.single().unwrap();
+        commands.entity(entity).try_activate_ability(tags.ability_stun);
     }
 }
 
@@ -210,27 +207,20 @@ fn move_enemies_towards_targets(
     }
 }
 
-/*--------------------------------+
- | Ability Step 1 (animation cue) |
- +--------------------------------*/
-fn pre_stun_cue(
-    mut cue: Query<(&mut PreStunCue, &BehaveCtx)>,
-    mut player: Query<&mut Transform, With<Player>>,
-    time: Res<Time>,
-    mut commands: Commands,
+/*--------------------------+
+ | Cast time (animation cue) |
+ +--------------------------*/
+fn stun_cast_cue(
+    mut player: Query<(&mut Transform, &ActiveAbilities<Stats>), With<Player>>,
+    tags: Res<StunTags>,
 ) {
-    // Simple bounce cue for 1 second before casting
-    let mut player = player.single_mut().unwrap();
-    if let Ok((mut cue, ctx)) = cue.single_mut() {
-        cue.tick(time.delta());
-        player.translation.y = (cue.fraction() * 10.).sin().abs();
-
-        if cue.finished() {
-            player.translation.y = 0.;
-            // Move on to next behavior tree node.
-            commands.trigger(ctx.success());
-        }
-    }
+    // Simple bounce cue while the stun is casting
+    let (mut player, active) = player.single_mut().unwrap();
+    let casting = active.get_by_tag(tags.ability_stun).and_then(|a| a.cast_fraction());
+    player.translation.y = match casting {
+        Some(fraction) => (fraction * 10.).sin().abs(),
+        None => 0.,
+    };
 }
 
 /*---------------------------------------+
@@ -238,21 +228,15 @@ fn pre_stun_cue(
  +---------------------------------------*/
 fn trigger_stun(
     trigger: Trigger<BehaveTrigger<StunTrigger>>,
-    mut player: Query<(Entity, &mut ActiveTags, &Transform, &CurrentAbility<Stats>), With<Player>>,
-    enemies: Query<(Entity, &Transform), With<Enemy>>,
+    player: Query<(Entity, &Transform), With<Player>>,
+    area: AbilityAreaQuery,
+    enemies: Query<(), With<Enemy>>,
     tags: Res<StunTags>,
     mut commands: Commands,
 ) {
-    // Handle effects when abiity actually executes
-    
-    // Unblock player movement
-    let (
-        player,
-        mut player_tags,
-        player_transform,
-        current
-    ) = player.single_mut().unwrap();
-    player_tags.remove(tags.character_movement_blocked_casting);
+    // Handle effects when abiity actually executes.
+    // The casting tag has already been removed by the plugin.
+    let (player, player_transform) = player.single().unwrap();
 
     // Stun enemies in range
     let range = 4.;
@@ -260,54 +244,20 @@ fn trigger_stun(
         tags.character_movement_blocked_stunned,
         Some(5.0)
     );
-    for (enemy, enemy_transform) in enemies.iter() {
-        let d = (enemy_transform.translation - player_transform.translation).length();
-        if d <= range {
-            commands.trigger(AddEffect(AddEffectData {
-                target_entity: enemy,
-                effect: stun_effect.clone(),
-                source_entity: Some(player),
-            }));
-        }
+    let shape = AreaShape::Sphere { center: player_transform.translation, radius: range };
+    for enemy in area.query(&shape, &TargetFilter::new()) {
+        if !enemies.contains(enemy) { continue }
+        commands.trigger(AddEffect(AddEffectData {
+            target_entity: enemy,
+            effect: stun_effect.clone(),
+            source_entity: Some(player),
+        }));
     }
 
-    // Add cooldown tag to prevent re-casting for 5 seconds
-    commands.trigger(
-        AddEffect(AddEffectData::<Stats>::new(
-            player,
-            GameplayEffect::tag_effect(
-                tags.ability_stun_cooldown,
-                Some(5.0),
-            ),
-            None,
-        ))
-    );
-
-    // Pay mana cost
-    // We defined this when we register.  All that really
-    // does is ensure we have enough to pay the cost before
-    // we can execute it.  When it is time to pay we still
-    // have to do that manually.
-    commands.trigger(
-        AddEffect(AddEffectData::<Stats>::new(
-            player,
-            GameplayEffect::new(
-                None,
-                Stats::Mana,
-                EffectMagnitude::Fixed(-25.),
-                EffectCalculation::Additive,
-                EffectDuration::Immediate,
-            ),
-            None,
-        ))
-    );
-
     // Finalize
+    // This is the last node, so the tree finishes and the ability ends on its own
     let ctx = trigger.event().ctx();
     commands.trigger(ctx.success());
-
-    let ability = current.as_ref().unwrap().clone();
-    commands.trigger(EndAbility{ entity: player, ability });
 }
 
 /*------------------------+
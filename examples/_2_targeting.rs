@@ -2,7 +2,7 @@ use std::time::Duration;
 
 /// This example demonstrates an ability with targeting and item costs.
 /// Use WASD to move and space bar to execute the ability.
-/// Use the same keys for targeting.
+/// Use the same keys for targeting, space to confirm and escape to cancel.
 /// You will have 10 grenades
  
 use bevy::prelude::*;
@@ -17,14 +17,11 @@ use shared::{SharedPlugin, MoveTarget, Player, Stats, Enemy};
 const MOVE_SPEED: f32 = 2.;
 
 
-#[derive(Component, Clone)]
-struct WaitForTargetConfirmation;
-
 #[derive(Component, Clone)]
 struct WaitForImpact;
 
 #[derive(Component)]
-struct GrenadeTarget(Vec3);
+struct Grenade;
 
 #[derive(Component)]
 struct TargetingReticle;
@@ -58,7 +55,6 @@ fn main() {
 
     let grenade_tree = tree!{
         Behave::Sequence => {
-            Behave::spawn(WaitForTargetConfirmation),
             Behave::spawn(WaitForImpact),
             // Trigger the effect on enemies
             Behave::trigger(Explode)
@@ -67,8 +63,12 @@ fn main() {
     let grenade_ability = AbilityDefinition::<Stats>::new(grenade_ability)
         .adds_tags([throwing])
         .blocked_by([throwing])
+        // Pick a point within 10 units before the tree starts
+        .with_targeting(TargetingMode::Point { range: 10. })
         .with_execution_tree(grenade_tree)
-        .with_item_cost(ItemCost { item_id: 1, amount: 1 });
+        .with_item_cost(ItemCost { item_id: 1, amount: 1 })
+        // Grenades are taken from the inventory when the throw starts
+        .with_cost_policy(CostPolicy::OnActivate);
 
         
     /*--------------------------+
@@ -105,7 +105,6 @@ fn setup_player(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    abilities: Res<AbilityRegistry<Stats>>,
     tags: Res<Tags>,
 ) {
     let mut inventory = AbilityItems::new();
@@ -123,10 +122,8 @@ fn setup_player(
         Player,
         ActiveTags::new(),
         ActiveEffects::<Stats>::new(None),
-        GrantedAbilities::<Stats>::from_tags(
-            [tags.grenade_ability], &abilities
-        ),
-        CurrentAbility::<Stats>::default(),
+        GrantedAbilities::<Stats>::innate([tags.grenade_ability]),
+        ActiveAbilities::<Stats>::default(),
         inventory,
     ));
 }
@@ -169,16 +166,14 @@ fn player_movement(
  | Ability Input |
  +---------------*/
 fn execute_grenade_ability(
-    player: Query<(Entity, &GrantedAbilities<Stats>), With<Player>>,
+    player: Query<Entity, With<Player>>,
     input: Res<ButtonInput<KeyCode>>,
     tags: Res<Tags>,
     mut commands: Commands,
 ) {
     if input.just_pressed(KeyCode::Space) {
-        let (entity, abilities) = player.single().unwrap();
-        let grenade_def = abilities.get_from_tag(tags.grenade_ability).unwrap();
-        let ability = Ability::from(&grenade_def);
-        commands.trigger(TryExecuteAbility { entity, ability });
+        let entity = player.single().unwrap();
+        commands.entity(entity).try_activate_ability(tags.grenade_ability);
     }
 }
 
@@ -196,81 +191,72 @@ fn move_enemies_towards_targets(
     }
 }
 
-/*---------------------------+
- | Ability Step 1 (targetng) |
- +---------------------------*/
+/*------------------------+
+ | Targeting phase (aim) |
+ +------------------------*/
 fn targeting_reticle(
-    mut targeter: Query<&BehaveCtx, With<WaitForTargetConfirmation>>,
-    mut reticle: Query<(Entity, &mut Transform), With<TargetingReticle>>,
+    player: Query<(Entity, &Transform, &ActiveAbilities<Stats>), With<Player>>,
+    mut reticle: Query<(Entity, &mut Transform), (With<TargetingReticle>, Without<Player>)>,
+    tags: Res<Tags>,
     time: Res<Time>,
     input: Res<ButtonInput<KeyCode>>,
     mut commands: Commands,
-    mut initialized: Local<bool>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
     mut gizmos: Gizmos,
-    player: Query<&Transform, (With<Player>, Without<TargetingReticle>)>,
 ) {
-    if let Ok(ctx) = targeter.single_mut() {
-        if !*initialized {
-            *initialized = true;
-            commands.spawn((Transform::default(), TargetingReticle));
+    // The plugin pauses the ability until the target is confirmed
+    let (player, player_transform, active) = player.single().unwrap();
+    let targeting = active.get_by_tag(tags.grenade_ability).is_some_and(|a| a.is_targeting());
+    let Ok((reticle, mut transform)) = reticle.single_mut() else {
+        if targeting {
+            commands.spawn((Transform::from_translation(player_transform.translation), TargetingReticle));
         }
+        return;
+    };
+    if !targeting {
+        commands.entity(reticle).despawn();
+        return;
+    }
 
-        // Did we trigger the throw?
-        if input.just_pressed(KeyCode::Space) {
-            *initialized = false;
-            // Move to next b tree node
-
-            // Spawn grenade object, set target
-            if let Ok((reticle, reticle_transform)) = reticle.single() {
-                let grenade_mesh = Mesh3d(meshes.add(Sphere::new(0.2).mesh()));
-                let grenade_material = MeshMaterial3d(materials.add(StandardMaterial {
-                    base_color: Color::LinearRgba(LinearRgba { red: 1., green: 0.3, blue: 0.9, alpha: 1. }),
-                    ..default()
-                }));
-                let player = player.single().unwrap();
-                commands.spawn((
-                    GrenadeTarget(reticle_transform.translation),
-                    Transform::from_translation(player.translation),
-                    grenade_mesh, grenade_material,
-                ));
-
-                // Despawn reticle
-                commands.entity(reticle).despawn();
-            }
-
-            commands.trigger(ctx.success());
-        }
+    // Did we trigger the throw?  The plugin checks the range and stores the point
+    // on the ability, then starts the execution tree.
+    if input.just_pressed(KeyCode::Space) {
+        commands.trigger(ConfirmTarget {
+            entity: player,
+            ability: tags.grenade_ability,
+            data: TargetData::Point(transform.translation),
+        });
+        return;
+    }
+    if input.just_pressed(KeyCode::Escape) {
+        commands.trigger(CancelTarget { entity: player, ability: tags.grenade_ability });
+        return;
     }
 
     // Handle reticle input
-    if let Ok((_, mut transform)) = reticle.single_mut() {
-        let mut vel = Vec3::ZERO;
-        if input.pressed(KeyCode::KeyA) {
-            vel += Vec3::X;
-        }
-        if input.pressed(KeyCode::KeyD) {
-            vel -= Vec3::X;
-        }
-        if input.pressed(KeyCode::KeyW) {
-            vel += Vec3::Z;
-        }
-        if input.pressed(KeyCode::KeyS) {
-            vel -= Vec3::Z;
-        }
-        if vel != Vec3::ZERO {
-            vel = vel.normalize();
-        }
-        transform.translation += 2. * vel * MOVE_SPEED * time.delta_secs();
-
-        let mut isometry = Isometry3d::from_translation(transform.translation);
-        isometry.rotation = Quat::from_rotation_x(90_f32.to_radians());
-        gizmos.circle(
-            isometry,
-            4., Color::linear_rgb(1., 0., 1.)
-        );
+    let mut vel = Vec3::ZERO;
+    if input.pressed(KeyCode::KeyA) {
+        vel += Vec3::X;
+    }
+    if input.pressed(KeyCode::KeyD) {
+        vel -= Vec3::X;
+    }
+    if input.pressed(KeyCode::KeyW) {
+        vel += Vec3::Z;
     }
+    if input.pressed(KeyCode::KeyS) {
+        vel -= Vec3::Z;
+    }
+    if vel != Vec3::ZERO {
+        vel = vel.normalize();
+    }
+    transform.translation += 2. * vel * MOVE_SPEED * time.delta_secs();
+
+    let mut isometry = Isometry3d::from_translation(transform.translation);
+    isometry.rotation = Quat::from_rotation_x(90_f32.to_radians());
+    gizmos.circle(
+        isometry,
+        4., Color::linear_rgb(1., 0., 1.)
+    );
 }
 
 fn parabola(start: Vec3, end: Vec3, max_height: f32, t: f32) -> Vec3 {
@@ -279,29 +265,38 @@ fn parabola(start: Vec3, end: Vec3, max_height: f32, t: f32) -> Vec3 {
     (1.0 - t) * (1.0 - t) * start + 2.0 * (1.0 - t) * t * control + t * t * end
 }
 /*---------------------------------+
- | Ability Step 2 (launch grenade) |
+ | Ability Step 1 (launch grenade) |
  +---------------------------------*/
 fn grenade_in_flight(
     mut ctx: Query<&BehaveCtx, With<WaitForImpact>>,
-    mut player: Query<(&Transform, &mut AbilityItems), With<Player>>,
-    mut grenade: Query<(&mut Transform, &GrenadeTarget), Without<Player>>,
+    player: Query<(&Transform, &ActiveAbilities<Stats>), With<Player>>,
+    mut grenade: Query<&mut Transform, (With<Grenade>, Without<Player>)>,
     mut commands: Commands,
     time: Res<Time>,
     mut initialized: Local<bool>,
     mut timer: Local<Timer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
     if let Ok(ctx) = ctx.single_mut() {
-        let (player, mut items) = player.single_mut ().unwrap();
+        let (player, active) = player.single().unwrap();
+        // The confirmed target point is stored on the running ability
+        let Some(target) = active.get_by_ctx(ctx).and_then(|a| a.target_data.point()) else { return };
         if !*initialized {
             *initialized = true;
-
-            // pay cost
-            let grenades = items.get_mut(&1).unwrap();
-            // This should never go below 0 because of the cost checking in the BevyAbilities systems
-            // But just in case we can use saturating sub 
-            *grenades = grenades.saturating_sub(1);
-
             timer.set_duration(Duration::from_secs(2));
+
+            // Spawn grenade object
+            let grenade_mesh = Mesh3d(meshes.add(Sphere::new(0.2).mesh()));
+            let grenade_material = MeshMaterial3d(materials.add(StandardMaterial {
+                base_color: Color::LinearRgba(LinearRgba { red: 1., green: 0.3, blue: 0.9, alpha: 1. }),
+                ..default()
+            }));
+            commands.spawn((
+                Grenade,
+                Transform::from_translation(player.translation),
+                grenade_mesh, grenade_material,
+            ));
         }
         timer.tick(time.delta());
 
@@ -310,37 +305,42 @@ fn grenade_in_flight(
             timer.reset();
             commands.trigger(ctx.success());
         }
-        if let Ok((mut transform, target)) = grenade.single_mut() {
-            transform.translation = parabola(player.translation, target.0, 3., timer.fraction());
+        if let Ok(mut transform) = grenade.single_mut() {
+            transform.translation = parabola(player.translation, target, 3., timer.fraction());
         }
     }
 }
 
 /*-------------------------------+
- | Ability step 3 (kill enemies) |
+ | Ability step 2 (kill enemies) |
  +-------------------------------*/
 fn explode(
     trigger: Trigger<BehaveTrigger<Explode>>,
-    grenade: Query<(Entity, &GrenadeTarget)>,
-    enemies: Query<(Entity, &Transform), With<Enemy>>,
-    player: Query<(Entity, &CurrentAbility<Stats>), With<Player>>,
+    grenade: Query<Entity, With<Grenade>>,
+    abilities: Query<&ActiveAbilities<Stats>>,
+    area: AbilityAreaQuery,
+    enemies: Query<(), With<Enemy>>,
     mut commands: Commands,
 ) {
     let ctx = trigger.event().ctx();
-    let (grenade_entity, target) = grenade.single().unwrap();
+    let grenade_entity = grenade.single().unwrap();
     commands.entity(grenade_entity).despawn();
 
+    // The target point was stored on the ability when it was confirmed
+    let target = abilities.get(ctx.target_entity()).ok()
+        .and_then(|a| a.get_by_ctx(ctx))
+        .and_then(|a| a.target_data.point())
+        .unwrap();
+
     // Stun enemies in range
     let range = 4.;
-    for (enemy, xform) in enemies.iter() {
-        let d = (xform.translation - target.0).length();
-        if d <= range {
+    let shape = AreaShape::Sphere { center: target, radius: range };
+    for enemy in area.query(&shape, &TargetFilter::new()) {
+        if enemies.contains(enemy) {
             commands.entity(enemy).despawn();
         }
     }
 
-    // Add cooldown tag to prevent re-casting for 5 seconds
+    // The tree is finished after this node, which ends the ability
     commands.trigger(ctx.success());
-    let (player, current) = player.single().unwrap();
-    commands.trigger(EndAbility{ entity: player, ability: current.as_ref().unwrap().clone() });
 }
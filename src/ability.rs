@@ -1,68 +1,436 @@
-use bevy::prelude::*;
+use bevy::{ecs::{component::{Mutable, StorageType}, system::SystemParam}, platform::collections::HashMap, prelude::*};
 use bevy_hierarchical_tags::prelude::*;
 use bevy_gameplay_effects::prelude::*;
 use smallvec::SmallVec;
-use crate::{costs::{AbilityCost, AbilityItems}, prelude::*};
+use crate::{
+    activation::ActivationPolicy,
+    buffer::AbilityInputBuffer,
+    casting::{AbilityCastTime, CastState},
+    costs::{AbilityCost, AbilityItems, CostPayer, CostPolicy, StatReservations},
+    channel::ChannelState,
+    combo::{AbilityCombos, ComboState},
+    cooldowns::{AbilityCooldown, AbilityCooldowns},
+    charges::{AbilityChargeConfig, AbilityCharges},
+    levels::ScalableValue,
+    tags::tag_matches,
+    target_data::TargetData,
+    targeting::TargetingMode,
+    triggers::GameplayEventData,
+    prelude::*,
+};
 use bevy_behave::prelude::*;
 
 
+/// Identifies one running instance of an ability on an entity
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct AbilityInstance(u32);
+
 #[derive(Clone)]
 pub struct Ability<T: StatTrait> {
     pub tags: AbilityTags,
     pub execution_tree: Option<Tree<Behave>>,
     pub costs: AbilityCost<T>,
+    pub cooldown: Option<AbilityCooldown>,
+    pub charges: Option<AbilityChargeConfig>,
+    pub magnitudes: HashMap<String, ScalableValue>,
+    pub activation: ActivationPolicy,
+    pub stat_drains: SmallVec<[StatCost<T>; 1]>,
+    pub tick_costs: SmallVec<[StatCost<T>; 1]>,
+    pub cast_time: Option<AbilityCastTime>,
+    pub input_priority: u8,
+    /// The payload of the gameplay event that activated this ability, if any
+    pub trigger_data: Option<GameplayEventData>,
+    /// What the ability is aimed at
+    pub target_data: TargetData,
+    pub targeting: Option<TargetingMode>,
     tree_entity: Option<Entity>,
-    //pub level: u8,
+    instance: Option<AbilityInstance>,
+    committed: bool,
+    pub(crate) level: u8,
+    pub(crate) channel: Option<ChannelState>,
+    pub(crate) cast: Option<CastState>,
+    pub(crate) awaiting_target: bool,
+    /// Activated by an AbilityTrigger rather than by input, so it is never buffered
+    pub(crate) triggered: bool,
+    /// Tags added by AddTags nodes, removed when the ability ends
+    pub(crate) node_tags: SmallVec<[TagId; 2]>,
+}
+
+impl<T: StatTrait> Ability<T> {
+    /// Build the ability at a specific level.  Ability::from uses level 1.
+    /// When executed the level is taken from the entity's GrantedAbilities.
+    pub fn at_level(definition: &AbilityDefinition<T>, level: u8) -> Self {
+        let mut ability = Self::from(definition);
+        ability.level = level;
+        ability
+    }
+
+    /// Aim the ability before activating it with TryExecuteAbility
+    pub fn with_target_data(mut self, target_data: TargetData) -> Self {
+        self.target_data = target_data;
+        self
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// A named magnitude from the definition, scaled to this ability's level
+    pub fn magnitude(&self, name: &str) -> Option<f32> {
+        self.magnitudes.get(name).map(|m| m.at(self.level))
+    }
+
+    /// True once the ability is committed: its cooldown has started and, depending
+    /// on the CostPolicy, its costs have been paid by the plugin
+    pub fn is_committed(&self) -> bool {
+        self.committed
+    }
+
+    /// The handle of this running instance.  None until the ability executes.
+    pub fn instance(&self) -> Option<AbilityInstance> {
+        self.instance
+    }
+
+    /// Ticks completed by a channeled ability.  Read it from EndAbility to see how far the channel got.
+    pub fn ticks(&self) -> u32 {
+        self.channel.as_ref().map(|c| c.ticks).unwrap_or(0)
+    }
+
+    /// True while the ability is waiting for ConfirmTarget, before its cast or execution tree starts
+    pub fn is_targeting(&self) -> bool {
+        self.awaiting_target
+    }
+
+    /// True while the ability is in its cast time, before the execution tree starts
+    pub fn is_casting(&self) -> bool {
+        self.cast.is_some()
+    }
+
+    /// Cast progress from 0 to 1, None if the ability isn't casting.  Use it to draw cast bars.
+    pub fn cast_fraction(&self) -> Option<f32> {
+        self.cast.as_ref().map(|c| c.timer.fraction())
+    }
+
+    /// Seconds left in the cast, None if the ability isn't casting
+    pub fn cast_remaining(&self) -> Option<f32> {
+        self.cast.as_ref().map(|c| c.timer.remaining_secs())
+    }
+
+    /// The spawned execution tree, if any
+    pub fn tree_entity(&self) -> Option<Entity> {
+        self.tree_entity
+    }
+
+    /// Pay the costs if the plugin is responsible for them, use a charge and start the cooldown
+    pub(crate) fn commit(
+        &mut self,
+        entity: Entity,
+        payer: CostPayer<T>,
+        cooldowns: &mut AbilityCooldowns,
+        charges: &mut AbilityCharges,
+        active_tags: &mut ActiveTags,
+        commands: &mut Commands,
+    ) {
+        if self.costs.policy != CostPolicy::Never {
+            self.costs.pay(self.level, entity, payer, commands);
+        }
+        if let Some(config) = &self.charges {
+            let remaining = charges.consume(self.tags.ability, config);
+            commands.trigger(AbilityChargeConsumed{ entity, ability: self.tags.ability, remaining });
+        }
+        if let Some(cooldown) = &self.cooldown {
+            cooldowns.start(self.tags.ability, cooldown, self.level, active_tags);
+        }
+        self.committed = true;
+    }
+
+    /// Commit an ability that has already started, e.g. when its cast completes.
+    /// If it can no longer pay it ends as Failed and false is returned.
+    pub(crate) fn try_commit(
+        &mut self,
+        entity: Entity,
+        payer: CostPayer<T>,
+        cooldowns: &mut AbilityCooldowns,
+        charges: &mut AbilityCharges,
+        active_tags: &mut ActiveTags,
+        commands: &mut Commands,
+    ) -> bool {
+        if self.costs.can_pay(self.level, payer.stats, payer.items.as_deref(), payer.reserved).is_err() {
+            commands.trigger(EndAbility{
+                entity,
+                ability: self.clone(),
+                outcome: AbilityOutcome::Failed,
+            });
+            return false;
+        }
+        self.commit(entity, payer, cooldowns, charges, active_tags, commands);
+        true
+    }
+
+    /// Start the cast, or the execution tree if there is no cast time
+    pub(crate) fn begin(
+        &mut self,
+        entity: Entity,
+        active_tags: &mut ActiveTags,
+        tag_counts: &mut AbilityTagCounts,
+        commands: &mut Commands,
+    ) {
+        let Some(cast_time) = self.cast_time.as_ref() else {
+            self.spawn_tree(entity, commands);
+            return;
+        };
+        // The execution tree waits for the cast to complete
+        let duration = cast_time.duration.at(self.level);
+        // Counted, as another ability may be casting with the same tags
+        cast_time.tags.iter().for_each(|t| tag_counts.add(*t, active_tags));
+        self.cast = Some(CastState::new(duration));
+        if let Some(instance) = self.instance {
+            commands.trigger(CastStarted{ entity, ability: self.tags.ability, instance, duration });
+        }
+    }
+
+    /// Spawn the execution tree as a child of the entity
+    pub(crate) fn spawn_tree(&mut self, entity: Entity, commands: &mut Commands) {
+        if let Some(tree) = &self.execution_tree {
+            let tree = commands.spawn(BehaveTree::new(tree.clone())).id();
+            commands.entity(entity).add_child(tree);
+            self.tree_entity = Some(tree);
+        }
+    }
+
+    /// Strip the added tags and despawn the execution tree
+    pub(crate) fn stop(
+        &self,
+        entity: Entity,
+        active_tags: &mut ActiveTags,
+        tag_counts: &mut AbilityTagCounts,
+        commands: &mut Commands,
+    ) {
+        // Other running abilities, or their nodes, may still hold the same tags
+        self.tags.add.iter().for_each(|t| tag_counts.remove(*t, active_tags));
+        self.node_tags.iter().for_each(|t| tag_counts.remove(*t, active_tags));
+        if self.is_casting() {
+            if let Some(cast_time) = &self.cast_time {
+                cast_time.tags.iter().for_each(|t| tag_counts.remove(*t, active_tags));
+            }
+            if let Some(instance) = self.instance {
+                commands.trigger(CastInterrupted{ entity, ability: self.tags.ability, instance });
+            }
+        }
+        if let Some(tree) = self.tree_entity {
+            commands.entity(tree).despawn();
+        }
+    }
 }
 
 impl<T: StatTrait> From<&AbilityDefinition<T>> for Ability<T> {
     fn from(value: &AbilityDefinition<T>) -> Self {
-        let AbilityDefinition::<T> { tags, execution_tree,  costs } = value;
+        let AbilityDefinition::<T> {
+            tags,
+            execution_tree,
+            costs,
+            cooldown,
+            charges,
+            magnitudes,
+            triggers: _,
+            activation,
+            stat_drains,
+            tick_costs,
+            cast_time,
+            input_priority,
+            targeting,
+        } = value;
+        let channel = match activation {
+            ActivationPolicy::Channeled { duration, tick_interval } => {
+                Some(ChannelState::new(*duration, *tick_interval))
+            }
+            _ => None,
+        };
         Self {
             tags: tags.clone(),
             costs: costs.clone(),
+            cooldown: cooldown.clone(),
+            charges: charges.clone(),
+            magnitudes: magnitudes.clone(),
             execution_tree: execution_tree.clone(),
+            activation: *activation,
+            stat_drains: stat_drains.clone(),
+            tick_costs: tick_costs.clone(),
+            cast_time: cast_time.clone(),
+            input_priority: *input_priority,
+            trigger_data: None,
+            target_data: TargetData::None,
+            targeting: targeting.clone(),
             tree_entity: None,
+            instance: None,
+            committed: false,
+            level: 1,
+            channel,
+            cast: None,
+            awaiting_target: false,
+            triggered: false,
+            node_tags: SmallVec::new(),
         }
     }
 }
 
-#[derive(Component, Deref, DerefMut)]
+/// The abilities currently running on an entity.
+/// Several abilities can run at once, each identified by its AbilityInstance.
+#[derive(Component)]
+#[require(AbilityCooldowns, AbilityCharges, AbilityTriggerState, PassiveState, ComboState, AbilityTagCounts)]
+pub struct ActiveAbilities<T: StatTrait> {
+    pub(crate) running: SmallVec<[Ability<T>; 4]>,
+    next_instance: u32,
+    pub(crate) reserved: StatReservations<T>,
+}
+
+impl<T: StatTrait> Default for ActiveAbilities<T> {
+    fn default() -> Self {
+        Self { running: SmallVec::new(), next_instance: 0, reserved: StatReservations::default() }
+    }
+}
+
+impl<T: StatTrait> ActiveAbilities<T> {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Ability<T>> {
+        self.running.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    pub fn get(&self, instance: AbilityInstance) -> Option<&Ability<T>> {
+        self.running.iter().find(|a| a.instance == Some(instance))
+    }
+
+    /// The first running instance of the ability with this exact tag
+    pub fn get_by_tag(&self, tag: TagId) -> Option<&Ability<T>> {
+        self.running.iter().find(|a| a.tags.ability == tag)
+    }
+
+    /// The running instance that owns this execution tree.
+    /// Use BehaveCtx::behave_entity() to find the ability from inside a tree node.
+    pub fn get_by_tree(&self, tree: Entity) -> Option<&Ability<T>> {
+        self.running.iter().find(|a| a.tree_entity == Some(tree))
+    }
+
+    /// The running instance that owns the tree a behave node belongs to.
+    /// Query the caster's ActiveAbilities with `ctx.target_entity()` and use this
+    /// to read the ability's level, magnitudes and target data from any node.
+    pub fn get_by_ctx(&self, ctx: &BehaveCtx) -> Option<&Ability<T>> {
+        self.get_by_tree(ctx.behave_entity())
+    }
+
+    pub fn is_active(&self, tag: TagId) -> bool {
+        self.get_by_tag(tag).is_some()
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Ability<T>> {
+        self.running.iter_mut()
+    }
+
+    pub(crate) fn get_mut(&mut self, instance: AbilityInstance) -> Option<&mut Ability<T>> {
+        self.running.iter_mut().find(|a| a.instance == Some(instance))
+    }
+
+    pub(crate) fn get_mut_by_ctx(&mut self, ctx: &BehaveCtx) -> Option<&mut Ability<T>> {
+        self.running.iter_mut().find(|a| a.tree_entity == Some(ctx.behave_entity()))
+    }
+
+    pub(crate) fn next_instance(&mut self) -> AbilityInstance {
+        let instance = AbilityInstance(self.next_instance);
+        self.next_instance = self.next_instance.wrapping_add(1);
+        instance
+    }
+
+    pub(crate) fn insert(&mut self, ability: Ability<T>) {
+        self.running.push(ability);
+    }
+
+    pub(crate) fn remove(&mut self, instance: AbilityInstance) -> Option<Ability<T>> {
+        let index = self.running.iter().position(|a| a.instance == Some(instance))?;
+        Some(self.running.remove(index))
+    }
+
+    /// Remove every running instance matching the predicate
+    pub(crate) fn remove_where(&mut self, mut f: impl FnMut(&Ability<T>) -> bool) -> SmallVec<[Ability<T>; 4]> {
+        let mut removed = SmallVec::new();
+        let mut i = 0;
+        while i < self.running.len() {
+            if f(&self.running[i]) {
+                removed.push(self.running.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    }
+}
+
+/// The last ability started on an entity, from before several abilities could run at once.
+/// It is still updated at the end of each frame for entities that have it, but only shows one of the running abilities.
+#[deprecated(note = "use ActiveAbilities, which holds every running ability")]
 pub struct CurrentAbility<T: StatTrait>(Option<Ability<T>>);
 
+// Written out rather than derived so the deprecation can be allowed on the impls
+#[allow(deprecated)]
+impl<T: StatTrait> Component for CurrentAbility<T> {
+    const STORAGE_TYPE: StorageType = StorageType::Table;
+    type Mutability = Mutable;
+}
+
+#[allow(deprecated)]
+impl<T: StatTrait> std::ops::Deref for CurrentAbility<T> {
+    type Target = Option<Ability<T>>;
+    fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+#[allow(deprecated)]
+impl<T: StatTrait> std::ops::DerefMut for CurrentAbility<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 }
+}
+
+#[allow(deprecated)]
 impl<T: StatTrait> Default for CurrentAbility<T> {
     fn default() -> Self {
         Self(None)
     }
 }
 
+#[allow(deprecated)]
 impl<T: StatTrait> CurrentAbility<T> {
     pub fn new() -> Self { Self::default() }
 }
 
-/// This component stores a list of ability definitions the entity is allowed to execute
-#[derive(Component, Clone, Deref, DerefMut)]
-pub struct GrantedAbilities<T: StatTrait>(SmallVec<[AbilityDefinition<T>; 16]>);
-
-impl<T: StatTrait> GrantedAbilities<T> {
-    pub fn new() -> Self {
-        Self(SmallVec::new())
+#[allow(deprecated, clippy::type_complexity)]
+pub(crate) fn sync_current_ability<T: StatTrait>(
+    mut casters: Query<(&ActiveAbilities<T>, &mut CurrentAbility<T>), Changed<ActiveAbilities<T>>>,
+) {
+    for (active, mut current) in casters.iter_mut() {
+        current.0 = active.running.last().cloned();
     }
+}
 
-    pub fn from_tags(tags: impl IntoIterator<Item = TagId>, registry: &AbilityRegistry<T>) -> Self {
-        let mut vec = SmallVec::new();
-        tags.into_iter().for_each(|t| {
-            if let Some(ability_def) = registry.get(&t) {
-                vec.push(ability_def.clone());
-            }
-        });
-        Self(vec)
-    }
+/// Which of an ability's tag requirements was not met
+pub(crate) enum TagFailure {
+    Missing(SmallVec<[TagId; 4]>),
+    Blocked(SmallVec<[TagId; 4]>),
+    Canceled(SmallVec<[TagId; 4]>),
+}
 
-    pub fn get_from_tag(&self, tag: TagId) -> Option<AbilityDefinition<T>> {
-        if let Some(index) = self.iter().position(|x| x.tags.ability == tag) {
-            Some(self[index].clone())
-        } else { None }
+impl<T: StatTrait> From<TagFailure> for ActivationFailureReason<T> {
+    fn from(value: TagFailure) -> Self {
+        match value {
+            TagFailure::Missing(tags) => Self::MissingRequiredTags(tags),
+            TagFailure::Blocked(tags) => Self::BlockedByTags(tags),
+            TagFailure::Canceled(tags) => Self::CanceledByTags(tags),
+        }
     }
 }
 
@@ -70,101 +438,620 @@ pub(crate) fn ability_tags_ok(
     tags: &AbilityTags,
     tag_registry: &TagRegistry,
     active_tags: &ActiveTags,
-) -> bool {
+) -> Result<(), TagFailure> {
+    let present = |list: &[TagId]| -> SmallVec<[TagId; 4]> {
+        list.iter().filter(|t| active_tags.any_match(**t, tag_registry)).copied().collect()
+    };
+
     // Must have tags
-    active_tags.all_match_from(&tags.required, tag_registry) &&
+    let missing: SmallVec<[TagId; 4]> = tags.required.iter()
+        .filter(|t| !active_tags.any_match(**t, tag_registry))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(TagFailure::Missing(missing));
+    }
+
     // Must NOT have tags
-    active_tags.none_match_from(&tags.blocked_by, tag_registry) &&
-    active_tags.none_match_from(&tags.canceled_by, tag_registry) 
+    let blocking = present(&tags.blocked_by);
+    if !blocking.is_empty() {
+        return Err(TagFailure::Blocked(blocking));
+    }
+
+    // Would be cancelled straight away
+    let canceling = present(&tags.canceled_by);
+    if !canceling.is_empty() {
+        return Err(TagFailure::Canceled(canceling));
+    }
+
+    Ok(())
 }
 
-pub(crate) fn check_ability_constraints<T: StatTrait>(
-    trigger: Trigger<TryExecuteAbility<T>>,
-    tag_registry: Res<TagRegistry>,
-    stats: Query<&GameplayStats<T>>,
-    active_tags: Query<(&ActiveTags, &GrantedAbilities<T>)>,
-    items: Query<&AbilityItems>,
-    mut commands: Commands,
-) {
-    let TryExecuteAbility{ entity, ability } = trigger.event();
-    let tags = &ability.tags;
-    let costs = &ability.costs;
-    let Ok((active_tags, granted)) = active_tags.get(*entity) else { return };
+/// Runs every activation check without side effects
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn can_activate<T: StatTrait>(
+    ability: &Ability<T>,
+    granted: &GrantedAbilities<T>,
+    active: &ActiveAbilities<T>,
+    cooldowns: &AbilityCooldowns,
+    charges: &AbilityCharges,
+    active_tags: &ActiveTags,
+    tag_registry: &TagRegistry,
+    stats: Option<&GameplayStats<T>>,
+    items: Option<&AbilityItems>,
+) -> Result<(), ActivationFailureReason<T>> {
+    let tag = ability.tags.ability;
+    if !granted.contains(tag) {
+        return Err(ActivationFailureReason::NotGranted);
+    }
+    if active.is_active(tag) {
+        return Err(ActivationFailureReason::AlreadyActive);
+    }
+    if let Some(remaining) = cooldowns.remaining(tag) {
+        return Err(ActivationFailureReason::OnCooldown { remaining });
+    }
+    if ability.charges.as_ref().is_some_and(|c| charges.available(tag, c) == 0) {
+        return Err(ActivationFailureReason::NoCharges);
+    }
+    ability_tags_ok(&ability.tags, tag_registry, active_tags)?;
+    ability.costs.can_pay(ability.level, stats, items, &active.reserved)
+}
+
+/// The checks that waiting for the ability to end or for tags to change won't fix
+fn can_afford<T: StatTrait>(
+    ability: &Ability<T>,
+    active: &ActiveAbilities<T>,
+    cooldowns: &AbilityCooldowns,
+    charges: &AbilityCharges,
+    stats: Option<&GameplayStats<T>>,
+    items: Option<&AbilityItems>,
+) -> bool {
+    let tag = ability.tags.ability;
+    !cooldowns.is_on_cooldown(tag)
+        && ability.charges.as_ref().is_none_or(|c| charges.available(tag, c) != 0)
+        && ability.costs.can_pay(ability.level, stats, items, &active.reserved).is_ok()
+}
 
-    if !granted.iter().any(|g| g.tags.ability == tags.ability) { return }
-    if !ability_tags_ok(tags, &tag_registry, active_tags) { return }
+/// Everything needed to check and start an ability, shared by every way of activating one
+#[derive(SystemParam)]
+#[allow(clippy::type_complexity)]
+pub(crate) struct AbilityActivation<'w, 's, T: StatTrait> {
+    tag_registry: Res<'w, TagRegistry>,
+    stats: Query<'w, 's, &'static GameplayStats<T>>,
+    casters: Query<'w, 's, (
+        &'static mut ActiveTags,
+        &'static GrantedAbilities<T>,
+        &'static mut ActiveAbilities<T>,
+        &'static mut AbilityCooldowns,
+        &'static mut AbilityCharges,
+        &'static mut AbilityTagCounts,
+    )>,
+    items: Query<'w, 's, &'static mut AbilityItems>,
+}
 
-    let mut can_pay = true;
+impl<T: StatTrait> AbilityActivation<'_, '_, T> {
+    /// The caster's tags, grants and running abilities
+    pub(crate) fn caster(&self, entity: Entity) -> Option<(&ActiveTags, &GrantedAbilities<T>, &ActiveAbilities<T>)> {
+        let (active_tags, granted, active, ..) = self.casters.get(entity).ok()?;
+        Some((active_tags, granted, active))
+    }
 
-    if costs.stat_costs.iter().len() > 0 {
-        let Ok(stats) = stats.get(*entity) else { return };
-        for cost in costs.stat_costs.iter() {
-            if stats.get(cost.stat).current_value < cost.amount {
-                can_pay = false;
-            }
+    /// True if the activation failed only because the ability is already running or its
+    /// tags don't allow it yet, so retrying it later could succeed
+    pub(crate) fn can_wait_for(&self, entity: Entity, ability: &Ability<T>, reason: &ActivationFailureReason<T>) -> bool {
+        let waiting = matches!(reason,
+            ActivationFailureReason::AlreadyActive
+            | ActivationFailureReason::BlockedByTags(_)
+            | ActivationFailureReason::CanceledByTags(_)
+        );
+        if !waiting { return false }
+        let Ok((active_tags, granted, active, cooldowns, charges, _)) = self.casters.get(entity) else { return false };
+        // Still running, but it couldn't start once it ends either
+        if let Err(TagFailure::Missing(_)) = ability_tags_ok(&ability.tags, &self.tag_registry, active_tags) {
+            return false;
+        }
+        let Some(grant) = granted.get(ability.tags.ability) else { return false };
+        let mut ability = ability.clone();
+        grant.apply(&mut ability);
+        can_afford(&ability, active, cooldowns, charges, self.stats.get(entity).ok(), self.items.get(entity).ok())
+    }
+
+    /// Runs every activation check and starts the ability if they pass.
+    /// The ability always runs at the level and with the overrides it was granted with.
+    /// Activating a toggle that is already on turns it off instead.
+    pub(crate) fn try_execute(
+        &mut self,
+        entity: Entity,
+        mut ability: Ability<T>,
+        commands: &mut Commands,
+    ) -> Result<(), ActivationFailureReason<T>> {
+        let Ok((active_tags, granted, active, cooldowns, charges, _)) = self.casters.get(entity) else {
+            return Err(ActivationFailureReason::MissingComponent);
+        };
+        let toggled_off = active.get_by_tag(ability.tags.ability)
+            .filter(|_| ability.activation == ActivationPolicy::Toggle);
+        if let Some(running) = toggled_off {
+            commands.trigger(EndAbility{
+                entity,
+                ability: running.clone(),
+                outcome: AbilityOutcome::Succeeded,
+            });
+            return Ok(());
+        }
+        if let Some(granted) = granted.get(ability.tags.ability) {
+            granted.apply(&mut ability);
         }
+        can_activate(
+            &ability, granted, active, cooldowns, charges, active_tags, &self.tag_registry,
+            self.stats.get(entity).ok(), self.items.get(entity).ok(),
+        )?;
+        self.start(entity, ability, commands);
+        Ok(())
     }
 
-    if costs.item_costs.iter().len() > 0 {
-        let Ok(items) = items.get(*entity) else { return };
-        for cost in costs.item_costs.iter() {
-            let Some(&inventory) = items.get(&cost.item_id) else { return };
-            if inventory < cost.amount as u16 {
-                can_pay = false;
+    /// Restart a suspended passive.  It already paid its costs, cooldown and charges
+    /// when it first started, so it is not committed again.
+    pub(crate) fn resume(&mut self, entity: Entity, mut ability: Ability<T>, commands: &mut Commands) {
+        let Ok((_, granted, ..)) = self.casters.get(entity) else { return };
+        if let Some(granted) = granted.get(ability.tags.ability) {
+            granted.apply(&mut ability);
+        }
+        ability.committed = true;
+        self.start(entity, ability, commands);
+    }
+
+    fn start(&mut self, entity: Entity, mut ability: Ability<T>, commands: &mut Commands) {
+        let mut items = self.items.get_mut(entity).ok();
+        let stats = self.stats.get(entity).ok();
+        let Ok((mut active_tags, _, mut active, mut cooldowns, mut charges, mut tag_counts)) = self.casters.get_mut(entity) else {
+            return;
+        };
+        let instance = active.next_instance();
+        ability.instance = Some(instance);
+        // A target supplied with the activation is confirmed like any other, so it is checked
+        // against the mode and the validator, and the ability keeps waiting if it is rejected
+        let mut supplied = None;
+        match &ability.targeting {
+            Some(TargetingMode::SelfTarget) => ability.target_data = TargetData::Entity(entity),
+            Some(_) => {
+                ability.awaiting_target = true;
+                supplied = Some(std::mem::take(&mut ability.target_data)).filter(|data| !data.is_none());
+            }
+            None => {}
+        }
+        if ability.awaiting_target {
+            commands.trigger(TargetingStarted{ entity, ability: ability.tags.ability, instance });
+        } else {
+            // Commit here rather than in execute_ability so that no other ability
+            // can pass the cost check before this one has paid.
+            // Abilities that wait for a target or a cast commit once they are done waiting instead.
+            if !ability.committed && ability.costs.policy != CostPolicy::OnCommit && ability.cast_time.is_none() {
+                let payer = CostPayer { stats, items: items.as_deref_mut(), reserved: &mut active.reserved };
+                ability.commit(entity, payer, &mut cooldowns, &mut charges, &mut active_tags, commands);
             }
+            ability.begin(entity, &mut active_tags, &mut tag_counts, commands);
+        }
+        let tag = ability.tags.ability;
+        commands.trigger(ExecuteAbility{ entity, ability });
+        if let Some(data) = supplied {
+            commands.trigger(ConfirmTarget{ entity, ability: tag, data });
         }
     }
+}
 
-    if can_pay {
-        let mut ability = ability.clone();
-        if let Some(tree) = &ability.execution_tree {
-            let tree = commands.spawn(BehaveTree::new(tree.clone())).id();
-            commands.entity(*entity).add_child(tree);
-            ability.tree_entity = Some(tree);
+/// Everything needed to stop a running ability, shared by every way of ending one
+#[derive(SystemParam)]
+pub(crate) struct AbilityEnding<'w, 's, T: StatTrait> {
+    casters: Query<'w, 's, (
+        &'static mut ActiveAbilities<T>,
+        &'static mut ActiveTags,
+        &'static mut AbilityTagCounts,
+        &'static mut ComboState,
+    )>,
+    combos: Res<'w, AbilityCombos>,
+}
+
+impl<T: StatTrait> AbilityEnding<'_, '_, T> {
+    /// The caster's running abilities
+    pub(crate) fn active(&self, entity: Entity) -> Option<&ActiveAbilities<T>> {
+        self.casters.get(entity).ok().map(|(active, ..)| active)
+    }
+
+    /// Remove a running instance, strip what it added and move its combo on.
+    /// Returns None if the instance is no longer running.
+    pub(crate) fn stop(
+        &mut self,
+        entity: Entity,
+        instance: AbilityInstance,
+        succeeded: bool,
+        commands: &mut Commands,
+    ) -> Option<Ability<T>> {
+        let (mut active, mut tags, mut tag_counts, mut combo) = self.casters.get_mut(entity).ok()?;
+        let ended = active.remove(instance)?;
+        ended.stop(entity, &mut tags, &mut tag_counts, commands);
+        combo.ended(entity, ended.tags.ability, succeeded, &self.combos, &mut tags, commands);
+        Some(ended)
+    }
+
+    /// Stop every running instance matching the predicate, as failures
+    pub(crate) fn stop_where(
+        &mut self,
+        entity: Entity,
+        f: impl FnMut(&Ability<T>) -> bool,
+        commands: &mut Commands,
+    ) -> SmallVec<[Ability<T>; 4]> {
+        let Ok((mut active, mut tags, mut tag_counts, mut combo)) = self.casters.get_mut(entity) else {
+            return SmallVec::new();
+        };
+        let stopped = active.remove_where(f);
+        for ended in stopped.iter() {
+            ended.stop(entity, &mut tags, &mut tag_counts, commands);
+            combo.ended(entity, ended.tags.ability, false, &self.combos, &mut tags, commands);
         }
-        commands.trigger(ExecuteAbility{ entity: *entity, ability: ability });
+        stopped
     }
 }
 
+pub(crate) fn check_ability_constraints<T: StatTrait>(
+    trigger: Trigger<TryExecuteAbility<T>>,
+    mut activation: AbilityActivation<T>,
+    mut buffers: Query<&mut AbilityInputBuffer>,
+    mut commands: Commands,
+) {
+    let TryExecuteAbility{ entity, ability } = trigger.event();
+    let Err(reason) = activation.try_execute(*entity, ability.clone(), &mut commands) else { return };
+
+    // Hold on to activations that only have to wait for tags or for the ability to end,
+    // if the entity has an input buffer.
+    // Passives and abilities activated by an AbilityTrigger are not player input, so they are never buffered.
+    let bufferable = ability.activation != ActivationPolicy::Passive
+        && !ability.triggered
+        && buffers.contains(*entity)
+        && activation.can_wait_for(*entity, ability, &reason);
+    let buffered = bufferable && buffers.get_mut(*entity).is_ok_and(|mut buffer| {
+        buffer.push(ability.tags.ability, ability.input_priority, ability.target_data.clone())
+    });
+    if buffered {
+        commands.trigger(AbilityActivationBuffered{ entity: *entity, ability: ability.tags.ability });
+        return;
+    }
+    commands.trigger(AbilityActivationFailed{
+        entity: *entity,
+        ability: ability.tags.ability,
+        reason,
+    });
+}
+
+pub(crate) fn commit_ability<T: StatTrait>(
+    trigger: Trigger<CommitAbility>,
+    mut q: Query<(&mut ActiveAbilities<T>, &mut AbilityCooldowns, &mut AbilityCharges, &mut ActiveTags)>,
+    stats: Query<&GameplayStats<T>>,
+    mut items: Query<&mut AbilityItems>,
+    mut commands: Commands,
+) {
+    let CommitAbility{ entity, ability } = trigger.event();
+    let Ok((mut active, mut cooldowns, mut charges, mut active_tags)) = q.get_mut(*entity) else { return };
+    let ActiveAbilities { running, reserved, .. } = &mut *active;
+    let Some(running) = running.iter_mut().find(|a| {
+        a.tags.ability == *ability && !a.committed && a.costs.policy == CostPolicy::OnCommit
+    }) else { return };
+
+    let mut items = items.get_mut(*entity).ok();
+    let payer = CostPayer { stats: stats.get(*entity).ok(), items: items.as_deref_mut(), reserved };
+    // Could not pay at commit time, so the ability can't continue
+    running.try_commit(*entity, payer, &mut cooldowns, &mut charges, &mut active_tags, &mut commands);
+}
 
 pub(crate) fn end_ability<T: StatTrait>(
     trigger: Trigger<EndAbility<T>>,
+    mut ending: AbilityEnding<T>,
     mut commands: Commands,
-    mut current: Query<(&mut CurrentAbility<T>, &mut ActiveTags)>,
 ) {
-    let EndAbility{ entity, ability } = trigger.event();
-    if let Ok((mut current_ability, mut tags)) = current.get_mut(*entity) {
-        for tag in ability.tags.add.iter() {
-            tags.remove(*tag);
-        }
-        current_ability.0 = None;
-        if let Some(tree) = &ability.tree_entity {
-            commands.entity(*tree).despawn();
-        }
+    let EndAbility{ entity, ability, outcome } = trigger.event();
+    let Some(instance) = ability.instance else { return };
+    // Ignore ends for an instance that is no longer running, e.g. when user code
+    // ends it manually in the same frame its tree finishes.
+    ending.stop(*entity, instance, *outcome == AbilityOutcome::Succeeded, &mut commands);
+}
+
+pub(crate) fn cancel_ability<T: StatTrait>(
+    trigger: Trigger<CancelAbility>,
+    mut ending: AbilityEnding<T>,
+    registry: Res<TagRegistry>,
+    mut commands: Commands,
+) {
+    let CancelAbility{ entity, ability } = trigger.event();
+    // Cancelling a parent tag also cancels any of its child abilities
+    let cancelled = ending.stop_where(*entity, |a| tag_matches(a.tags.ability, *ability, &registry), &mut commands);
+    for running in cancelled {
+        commands.trigger(AbilityCancelled{ entity: *entity, ability: running });
     }
 }
-        
+
+pub(crate) fn cancel_ability_instance<T: StatTrait>(
+    trigger: Trigger<CancelAbilityInstance>,
+    mut ending: AbilityEnding<T>,
+    mut commands: Commands,
+) {
+    let CancelAbilityInstance{ entity, instance } = trigger.event();
+    let Some(running) = ending.stop(*entity, *instance, false, &mut commands) else { return };
+    commands.trigger(AbilityCancelled{ entity: *entity, ability: running });
+}
+
 pub(crate) fn execute_ability<T: StatTrait>(
     trigger: Trigger<ExecuteAbility<T>>,
-    mut q: Query<(&mut ActiveTags, &mut CurrentAbility<T>)>,
+    mut q: Query<(&mut ActiveTags, &mut AbilityTagCounts, &mut ActiveAbilities<T>, &mut ComboState)>,
+    combos: Res<AbilityCombos>,
 ) {
     let ExecuteAbility { entity, ability } = trigger.event();
-    if let Ok((mut tags, mut current)) = q.get_mut(*entity) {
-        ability.tags.add.iter().for_each(|t| tags.push(*t));
-        current.0 = Some(ability.clone());
+    if let Ok((mut tags, mut tag_counts, mut active, mut combo)) = q.get_mut(*entity) {
+        ability.tags.add.iter().for_each(|t| tag_counts.add(*t, &mut tags));
+        combo.started(ability.tags.ability, &combos, &mut tags);
+        active.insert(ability.clone());
     }
 }
 
 pub(crate) fn check_ability_canceled<T: StatTrait>(
-    q: Query<(Entity, &ActiveTags, &CurrentAbility<T>)>,
+    q: Query<(Entity, &ActiveTags, &ActiveAbilities<T>)>,
     registry: Res<TagRegistry>,
    mut commands: Commands,
 ) {
-    q.iter().for_each(|(entity, tags, current)| {
-        if let Some(ability) = &current.0 {
+    q.iter().for_each(|(entity, tags, active)| {
+        for ability in active.iter() {
+            let uninterruptible = ability.cast_time.as_ref().is_some_and(|c| !c.interruptible);
+            if ability.is_casting() && uninterruptible { continue }
             if ability.tags.canceled_by.any_match_from(tags, &registry) {
-                commands.trigger(EndAbility{ entity, ability: ability.clone() });
+                commands.trigger(EndAbility{
+                    entity,
+                    ability: ability.clone(),
+                    outcome: AbilityOutcome::Interrupted,
+                });
+            }
+        }
+    })
+}
+
+/// Ends abilities whose execution tree has run to completion
+pub(crate) fn check_ability_tree_finished<T: StatTrait>(
+    q: Query<(Entity, &ActiveAbilities<T>)>,
+    trees: Query<&BehaveFinished>,
+    mut commands: Commands,
+) {
+    q.iter().for_each(|(entity, active)| {
+        for ability in active.iter() {
+            let Some(tree) = ability.tree_entity else { continue };
+            if let Ok(finished) = trees.get(tree) {
+                let outcome = if finished.0 { AbilityOutcome::Succeeded } else { AbilityOutcome::Failed };
+                commands.trigger(EndAbility{ entity, ability: ability.clone(), outcome });
             }
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_behave::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+    use super::AbilityInstance;
+
+    #[test]
+    fn cancel_ability_stops_children_of_the_tag() {
+        let mut tags = TagRegistry::new();
+        let grenade = tags.register("Ability.Grenade");
+        let throwing = tags.register("Ability.Grenade.Throwing");
+        let aiming = tags.register("Character.State.Aiming");
+        let stun = tags.register("Ability.Stun");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(throwing).adds_tags([aiming]));
+        abilities.register(AbilityDefinition::new(stun));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityCancelled<TestStats>| e.ability.tags.ability);
+        let caster = spawn_caster(&mut app, [throwing, stun]);
+
+        try_execute(&mut app, caster, throwing);
+        try_execute(&mut app, caster, stun);
+        assert!(has_tag(&app, caster, aiming));
+
+        trigger(&mut app, CancelAbility { entity: caster, ability: grenade });
+        assert_eq!(recorded::<AbilityCancelled<TestStats>, TagId>(&app), vec![throwing]);
+        assert_eq!(running(&app, caster), 1);
+        assert!(!has_tag(&app, caster, aiming));
+    }
+
+    #[test]
+    fn cancel_ability_ignores_abilities_that_are_not_running() {
+        let mut tags = TagRegistry::new();
+        let stun = tags.register("Ability.Stun");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(stun));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityCancelled<TestStats>| e.ability.tags.ability);
+        let caster = spawn_caster(&mut app, [stun]);
+
+        trigger(&mut app, CancelAbility { entity: caster, ability: stun });
+        assert!(recorded::<AbilityCancelled<TestStats>, TagId>(&app).is_empty());
+    }
+
+    fn failure(e: &AbilityActivationFailed<TestStats>) -> &'static str {
+        match e.reason {
+            ActivationFailureReason::NotGranted => "not granted",
+            ActivationFailureReason::MissingRequiredTags(_) => "missing",
+            ActivationFailureReason::BlockedByTags(_) => "blocked",
+            ActivationFailureReason::CanceledByTags(_) => "canceled",
+            ActivationFailureReason::InsufficientStat { .. } => "stat",
+            ActivationFailureReason::InsufficientItem { .. } => "item",
+            ActivationFailureReason::MissingComponent => "component",
+            ActivationFailureReason::AlreadyActive => "active",
+            ActivationFailureReason::OnCooldown { .. } => "cooldown",
+            ActivationFailureReason::NoCharges => "charges",
+        }
+    }
+
+    #[test]
+    fn failures_report_why_activation_was_rejected() {
+        let mut tags = TagRegistry::new();
+        let dash = tags.register("Ability.Dash");
+        let grounded = tags.register("Character.State.Grounded");
+        let rooted = tags.register("Character.State.Rooted");
+        let stunned = tags.register("Character.State.Stunned");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(dash)
+            .required([grounded])
+            .blocked_by([rooted])
+            .canceled_by([stunned])
+            .with_stat_cost(StatCost::new(TestStats::Mana, 20.))
+        );
+        let mut app = app(tags, abilities);
+        record(&mut app, failure);
+        let caster = spawn_caster(&mut app, [dash]);
+        let ungranted = spawn_caster(&mut app, []);
+        let bare = app.world_mut().spawn_empty().id();
+
+        try_execute(&mut app, ungranted, dash);
+        try_execute(&mut app, bare, dash);
+        try_execute(&mut app, caster, dash);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(grounded);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(stunned);
+        try_execute(&mut app, caster, dash);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(rooted);
+        try_execute(&mut app, caster, dash);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(rooted);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(stunned);
+        try_execute(&mut app, caster, dash);
+
+        assert_eq!(
+            recorded::<AbilityActivationFailed<TestStats>, &str>(&app),
+            vec!["not granted", "component", "missing", "canceled", "blocked", "stat"]
+        );
+    }
+
+    #[test]
+    fn abilities_end_when_their_tree_finishes() {
+        let mut tags = TagRegistry::new();
+        let slash = tags.register("Ability.Slash");
+        let parry = tags.register("Ability.Parry");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(slash).with_execution_tree(tree! { Behave::AlwaysSucceed }));
+        abilities.register(AbilityDefinition::new(parry).with_execution_tree(tree! { Behave::AlwaysFail }));
+        let mut app = app(tags, abilities);
+        app.add_plugins(BehavePlugin::new(Update));
+        record(&mut app, |e: &EndAbility<TestStats>| (e.ability.tags.ability, e.outcome));
+        let caster = spawn_caster(&mut app, [slash, parry]);
+
+        try_execute(&mut app, caster, slash);
+        try_execute(&mut app, caster, parry);
+        update(&mut app);
+        update(&mut app);
+
+        let mut ended = recorded::<EndAbility<TestStats>, (TagId, AbilityOutcome)>(&app);
+        ended.sort_by_key(|(tag, _)| *tag == parry);
+        assert_eq!(ended, vec![(slash, AbilityOutcome::Succeeded), (parry, AbilityOutcome::Failed)]);
+        assert_eq!(running(&app, caster), 0);
+    }
+
+    fn instance_of(app: &App, entity: Entity, tag: TagId) -> Ability<TestStats> {
+        app.world().get::<ActiveAbilities<TestStats>>(entity).unwrap().get_by_tag(tag).unwrap().clone()
+    }
+
+    #[test]
+    fn abilities_run_side_by_side() {
+        let mut tags = TagRegistry::new();
+        let sprint = tags.register("Ability.Sprint");
+        let shout = tags.register("Ability.Shout");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(sprint));
+        abilities.register(AbilityDefinition::new(shout));
+        let mut app = app(tags, abilities);
+        record(&mut app, failure);
+        let caster = spawn_caster(&mut app, [sprint, shout]);
+
+        try_execute(&mut app, caster, sprint);
+        try_execute(&mut app, caster, shout);
+        try_execute(&mut app, caster, sprint);
+
+        let active = app.world().get::<ActiveAbilities<TestStats>>(caster).unwrap();
+        assert_eq!(active.len(), 2);
+        assert!(active.is_active(sprint) && active.is_active(shout));
+        let instance = active.get_by_tag(shout).unwrap().instance().unwrap();
+        assert_eq!(active.get(instance).unwrap().tags.ability, shout);
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, &str>(&app), vec!["active"]);
+    }
+
+    #[test]
+    fn ending_one_instance_leaves_the_others_running() {
+        let mut tags = TagRegistry::new();
+        let sprint = tags.register("Ability.Sprint");
+        let shout = tags.register("Ability.Shout");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(sprint));
+        abilities.register(AbilityDefinition::new(shout));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [sprint, shout]);
+
+        try_execute(&mut app, caster, sprint);
+        try_execute(&mut app, caster, shout);
+        let ability = instance_of(&app, caster, sprint);
+        trigger(&mut app, EndAbility { entity: caster, ability, outcome: AbilityOutcome::Succeeded });
+        let active = app.world().get::<ActiveAbilities<TestStats>>(caster).unwrap();
+        assert!(!active.is_active(sprint) && active.is_active(shout));
+
+        let instance = instance_of(&app, caster, shout).instance().unwrap();
+        trigger(&mut app, CancelAbilityInstance { entity: caster, instance });
+        assert_eq!(running(&app, caster), 0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn current_ability_mirrors_the_last_ability_started() {
+        let mut tags = TagRegistry::new();
+        let sprint = tags.register("Ability.Sprint");
+        let shout = tags.register("Ability.Shout");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(sprint));
+        abilities.register(AbilityDefinition::new(shout));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [sprint, shout]);
+        app.world_mut().entity_mut(caster).insert(CurrentAbility::<TestStats>::new());
+        let current = |app: &App| app.world().get::<CurrentAbility<TestStats>>(caster).unwrap().as_ref().map(|a| a.tags.ability);
+
+        try_execute(&mut app, caster, sprint);
+        try_execute(&mut app, caster, shout);
+        app.update();
+        assert_eq!(current(&app), Some(shout));
+
+        let ability = instance_of(&app, caster, shout);
+        trigger(&mut app, EndAbility { entity: caster, ability, outcome: AbilityOutcome::Succeeded });
+        app.update();
+        assert_eq!(current(&app), Some(sprint));
+    }
+
+    #[test]
+    fn shared_tags_stay_until_the_last_ability_holding_them_stops() {
+        let mut tags = TagRegistry::new();
+        let sprint = tags.register("Ability.Sprint");
+        let shout = tags.register("Ability.Shout");
+        let busy = tags.register("Character.State.Busy");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(sprint).adds_tags([busy]));
+        abilities.register(AbilityDefinition::new(shout).adds_tags([busy]));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [sprint, shout]);
+
+        try_execute(&mut app, caster, sprint);
+        try_execute(&mut app, caster, shout);
+        trigger(&mut app, CancelAbility { entity: caster, ability: sprint });
+        assert!(has_tag(&app, caster, busy));
+        trigger(&mut app, CancelAbility { entity: caster, ability: shout });
+        assert!(!has_tag(&app, caster, busy));
+    }
+
+    #[test]
+    fn instance_ids_wrap_around() {
+        let mut active = ActiveAbilities::<TestStats>::new();
+        active.next_instance = u32::MAX;
+        assert_eq!(active.next_instance(), AbilityInstance(u32::MAX));
+        assert_eq!(active.next_instance(), AbilityInstance(0));
+    }
+}
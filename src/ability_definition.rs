@@ -1,7 +1,18 @@
+use bevy::platform::collections::HashMap;
 use bevy_hierarchical_tags::TagId;
 use bevy_gameplay_effects::prelude::StatTrait;
 use smallvec::SmallVec;
-use crate::{costs::{AbilityCost, ItemCost, StatCost}, tags::AbilityTags};
+use crate::{
+    activation::ActivationPolicy,
+    casting::AbilityCastTime,
+    targeting::TargetingMode,
+    costs::{AbilityCost, CostPolicy, ItemCost, StatCost},
+    cooldowns::AbilityCooldown,
+    charges::AbilityChargeConfig,
+    levels::ScalableValue,
+    tags::AbilityTags,
+    triggers::AbilityTrigger,
+};
 use bevy_behave::prelude::*;
 
 
@@ -10,6 +21,23 @@ pub struct AbilityDefinition<T: StatTrait> {
     pub tags: AbilityTags,
     pub execution_tree: Option<Tree<Behave>>,
     pub costs: AbilityCost<T>,
+    pub cooldown: Option<AbilityCooldown>,
+    pub charges: Option<AbilityChargeConfig>,
+    /// Named values for your execution tree, e.g. "damage", scaled by ability level
+    pub magnitudes: HashMap<String, ScalableValue>,
+    /// Events that activate the ability automatically
+    pub triggers: SmallVec<[AbilityTrigger; 1]>,
+    pub activation: ActivationPolicy,
+    /// Stats paid every second while the ability runs, e.g. stamina for a sprint
+    pub stat_drains: SmallVec<[StatCost<T>; 1]>,
+    /// Stats paid on every tick of a channeled ability
+    pub tick_costs: SmallVec<[StatCost<T>; 1]>,
+    /// A wind-up phase before the execution tree starts
+    pub cast_time: Option<AbilityCastTime>,
+    /// Which activation an AbilityInputBuffer keeps when it is full, and retries first
+    pub input_priority: u8,
+    /// How the ability picks its target before it starts
+    pub targeting: Option<TargetingMode>,
 }
 
 impl<T: StatTrait> AbilityDefinition<T> {
@@ -18,9 +46,20 @@ impl<T: StatTrait> AbilityDefinition<T> {
             tags: AbilityTags::new(tag),
             costs: AbilityCost::<T> {
                 stat_costs: SmallVec::new(),
-                item_costs: SmallVec::new()
+                item_costs: SmallVec::new(),
+                policy: CostPolicy::default(),
             },
             execution_tree: None,
+            cooldown: None,
+            charges: None,
+            magnitudes: HashMap::new(),
+            triggers: SmallVec::new(),
+            activation: ActivationPolicy::default(),
+            stat_drains: SmallVec::new(),
+            tick_costs: SmallVec::new(),
+            cast_time: None,
+            input_priority: 0,
+            targeting: None,
         }
     }
 
@@ -39,13 +78,88 @@ impl<T: StatTrait> AbilityDefinition<T> {
         self
     }
 
-    /*
-    pub fn level(mut self, level: u8) -> Self {
-        self.level = level;
+    /// Choose if and when the plugin pays the costs for you. Defaults to CostPolicy::Never.
+    pub fn with_cost_policy(mut self, policy: CostPolicy) -> Self {
+        self.costs.policy = policy;
+        self
+    }
+
+    /// Choose how the ability is started and stopped. Defaults to ActivationPolicy::Standard.
+    pub fn with_activation_policy(mut self, policy: ActivationPolicy) -> Self {
+        self.activation = policy;
+        self
+    }
+
+    /// Drain `amount` of a stat per second while the ability runs.
+    /// If the stat runs out the ability is ended as Interrupted.
+    pub fn with_stat_drain(mut self, drain: StatCost<T>) -> Self {
+        self.stat_drains.push(drain);
+        self
+    }
+
+    /// Pay a stat cost on every tick of a channeled ability.
+    /// If it can't be paid the channel is ended as Interrupted.
+    pub fn with_tick_cost(mut self, cost: StatCost<T>) -> Self {
+        self.tick_costs.push(cost);
+        self
+    }
+
+    /// Add a cast time that must complete before the execution tree is spawned.
+    /// Track it with CastStarted, CastInterrupted and CastCompleted, or Ability::cast_fraction.
+    pub fn with_cast_time(mut self, cast_time: AbilityCastTime) -> Self {
+        self.cast_time = Some(cast_time);
+        self
+    }
+
+    /// Pause the ability after activation until a ConfirmTarget or CancelTarget arrives.
+    /// The confirmed target is written to the ability's target data.
+    pub fn with_targeting(mut self, mode: TargetingMode) -> Self {
+        self.targeting = Some(mode);
+        self
+    }
+
+    /// Priority of this ability in an AbilityInputBuffer.  Higher goes first.  Defaults to 0.
+    pub fn with_input_priority(mut self, priority: u8) -> Self {
+        self.input_priority = priority;
+        self
+    }
+
+    /// Blocks re-activation for `seconds` once the ability commits.
+    /// Query the remaining time with the AbilityCooldowns component.
+    pub fn with_cooldown(mut self, seconds: impl Into<ScalableValue>) -> Self {
+        let tag = self.cooldown.take().and_then(|c| c.tag);
+        self.cooldown = Some(AbilityCooldown { duration: seconds.into(), tag });
+        self
+    }
+
+    /// Adds this tag to ActiveTags while the cooldown runs.  Use with_cooldown to set the duration.
+    pub fn with_cooldown_tag(mut self, tag: TagId) -> Self {
+        let duration = self.cooldown.take().map(|c| c.duration).unwrap_or_default();
+        self.cooldown = Some(AbilityCooldown { duration, tag: Some(tag) });
+        self
+    }
+
+    /// Gives the ability `max` charges.  Each activation uses one and they
+    /// are restored one at a time, every `recharge_time` seconds.
+    /// With a `max` of 0 the ability can never be activated.
+    pub fn with_charges(mut self, max: u8, recharge_time: f32) -> Self {
+        self.charges = Some(AbilityChargeConfig { max, recharge_time });
+        self
+    }
+
+    /// Add a named value that execution tree systems can read with Ability::magnitude
+    pub fn with_magnitude(mut self, name: impl Into<String>, value: impl Into<ScalableValue>) -> Self {
+        self.magnitudes.insert(name.into(), value.into());
         self
     }
-     */
     
+    /// Activate the ability automatically when a tag is added or removed, or a gameplay event is sent.
+    /// Triggered activations go through the same checks as TryExecuteAbility.
+    pub fn triggered_by(mut self, trigger: AbilityTrigger) -> Self {
+        self.triggers.push(trigger);
+        self
+    }
+
     pub fn required(mut self, tags: impl IntoIterator<Item = TagId>) -> Self {
         tags.into_iter().for_each(|tag| self.tags.required.push(tag));
         self
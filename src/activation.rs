@@ -0,0 +1,224 @@
+use std::cmp::Reverse;
+use bevy::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::SmallVec;
+use crate::{
+    ability::AbilityActivation,
+    combo::{AbilityCombos, ComboState},
+    tags::tag_matches,
+    prelude::*,
+};
+
+
+/// Controls how an ability is started and stopped
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum ActivationPolicy {
+    /// Activated by TryExecuteAbility or a trigger, and ends when its tree finishes
+    #[default]
+    Standard,
+    /// Runs for as long as it is granted.  It is suspended while its required or
+    /// blocked_by tags don't allow it and resumes when they do.
+    Passive,
+    /// The first activation turns the ability on and the next one turns it off
+    Toggle,
+    /// Runs for `duration` seconds, triggering AbilityTick every `tick_interval` seconds
+    Channeled { duration: f32, tick_interval: f32 },
+}
+
+// Eq can't be derived for the f32 fields.  Channel durations are never NaN,
+// so PartialEq is already a full equivalence.
+impl Eq for ActivationPolicy {}
+
+/// Shortcuts for activating abilities from EntityCommands, e.g.
+/// `commands.entity(player).try_activate_ability(tags.fireball)`
+pub trait AbilityCommandsExt {
+    /// Triggers TryActivateAbilityByTag for this entity
+    fn try_activate_ability(&mut self, ability: TagId) -> &mut Self;
+    /// Triggers TryActivateAbilityByTag for this entity, aimed at a target
+    fn try_activate_ability_at(&mut self, ability: TagId, target_data: TargetData) -> &mut Self;
+    /// Triggers TryActivateAbilityByParentTag for this entity
+    fn try_activate_ability_matching(&mut self, parent: TagId) -> &mut Self;
+}
+
+impl AbilityCommandsExt for EntityCommands<'_> {
+    fn try_activate_ability(&mut self, ability: TagId) -> &mut Self {
+        self.try_activate_ability_at(ability, TargetData::None)
+    }
+
+    fn try_activate_ability_at(&mut self, ability: TagId, target_data: TargetData) -> &mut Self {
+        let entity = self.id();
+        self.commands().trigger(TryActivateAbilityByTag::new(entity, ability).with_target_data(target_data));
+        self
+    }
+
+    fn try_activate_ability_matching(&mut self, parent: TagId) -> &mut Self {
+        let entity = self.id();
+        self.commands().trigger(TryActivateAbilityByParentTag{ entity, parent });
+        self
+    }
+}
+
+pub(crate) fn try_activate_by_tag<T: StatTrait>(
+    trigger: Trigger<TryActivateAbilityByTag>,
+    granted: Query<&GrantedAbilities<T>>,
+    combo_state: Query<&ComboState>,
+    registry: Res<AbilityRegistry<T>>,
+    combos: Res<AbilityCombos>,
+    mut commands: Commands,
+) {
+    let TryActivateAbilityByTag{ entity, ability: tag, target_data } = trigger.event();
+    // A combo tag activates whichever step of the combo is next
+    let tag = match (combos.get(*tag), combo_state.get(*entity)) {
+        (Some(combo), Ok(state)) => state.resolve(combo).unwrap_or(*tag),
+        (Some(combo), Err(_)) => combo.steps.first().copied().unwrap_or(*tag),
+        _ => *tag,
+    };
+    let ability = granted.get(*entity).ok().and_then(|g| g.ability(tag, &registry));
+    let Some(ability) = ability else {
+        commands.trigger(AbilityActivationFailed::<T>{
+            entity: *entity,
+            ability: tag,
+            reason: ActivationFailureReason::NotGranted,
+        });
+        return;
+    };
+    let ability = ability.with_target_data(target_data.clone());
+    commands.trigger(TryExecuteAbility{ entity: *entity, ability });
+}
+
+pub(crate) fn try_activate_by_parent_tag<T: StatTrait>(
+    trigger: Trigger<TryActivateAbilityByParentTag>,
+    granted: Query<&GrantedAbilities<T>>,
+    registry: Res<AbilityRegistry<T>>,
+    tag_registry: Res<TagRegistry>,
+    mut activation: AbilityActivation<T>,
+    mut commands: Commands,
+) {
+    let TryActivateAbilityByParentTag{ entity, parent } = trigger.event();
+    let Ok(granted) = granted.get(*entity) else {
+        commands.trigger(AbilityActivationFailed::<T>{
+            entity: *entity,
+            ability: *parent,
+            reason: ActivationFailureReason::MissingComponent,
+        });
+        return;
+    };
+
+    // Try the highest input priority first, and the earliest grant on a tie
+    let mut candidates: SmallVec<[(&AbilityDefinition<T>, u32); 4]> = granted.iter()
+        .filter(|(tag, _)| tag_matches(*tag, *parent, &tag_registry))
+        .filter_map(|(tag, grant)| Some((registry.get(&tag)?, grant.order())))
+        .collect();
+    candidates.sort_by_key(|(definition, order)| (Reverse(definition.input_priority), *order));
+
+    // Report why the last candidate failed if none of them can activate
+    let mut reason = ActivationFailureReason::NotGranted;
+    for (definition, _) in candidates {
+        match activation.try_execute(*entity, Ability::from(definition), &mut commands) {
+            Ok(()) => return,
+            Err(err) => reason = err,
+        }
+    }
+    commands.trigger(AbilityActivationFailed::<T>{ entity: *entity, ability: *parent, reason });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    fn executed(app: &mut App) {
+        record(app, |e: &ExecuteAbility<TestStats>| e.ability.tags.ability);
+    }
+
+    #[test]
+    fn by_tag_activates_granted_abilities_at_their_granted_level() {
+        let mut tags = TagRegistry::new();
+        let fireball = tags.register("Ability.Fireball");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(fireball));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &ExecuteAbility<TestStats>| e.ability.level);
+        let caster = spawn_caster(&mut app, []);
+        app.world_mut().get_mut::<GrantedAbilities<TestStats>>(caster).unwrap().grant(fireball, 3);
+
+        trigger(&mut app, TryActivateAbilityByTag::new(caster, fireball));
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, u8>(&app), vec![3]);
+    }
+
+    #[test]
+    fn by_tag_reports_abilities_that_are_not_granted() {
+        let mut tags = TagRegistry::new();
+        let fireball = tags.register("Ability.Fireball");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(fireball));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| {
+            matches!(e.reason, ActivationFailureReason::NotGranted)
+        });
+        let caster = spawn_caster(&mut app, []);
+
+        trigger(&mut app, TryActivateAbilityByTag::new(caster, fireball));
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, bool>(&app), vec![true]);
+    }
+
+    #[test]
+    fn by_tag_resolves_combo_tags_to_the_first_step() {
+        let mut tags = TagRegistry::new();
+        let attack = tags.register("Ability.Attack");
+        let first = tags.register("Ability.Attack.First");
+        let second = tags.register("Ability.Attack.Second");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(first));
+        abilities.register(AbilityDefinition::new(second));
+        abilities.register_combo(AbilityCombo::new(attack, [first, second], 1.));
+        let mut app = app(tags, abilities);
+        executed(&mut app);
+        let caster = spawn_caster(&mut app, [first, second]);
+
+        trigger(&mut app, TryActivateAbilityByTag::new(caster, attack));
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![first]);
+    }
+
+    #[test]
+    fn parent_tag_prefers_input_priority_then_grant_order() {
+        let mut tags = TagRegistry::new();
+        let attack = tags.register("Ability.Attack");
+        let jab = tags.register("Ability.Attack.Jab");
+        let hook = tags.register("Ability.Attack.Hook");
+        let uppercut = tags.register("Ability.Attack.Uppercut");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(jab));
+        abilities.register(AbilityDefinition::new(hook));
+        abilities.register(AbilityDefinition::new(uppercut).with_input_priority(1));
+        let mut app = app(tags, abilities);
+        executed(&mut app);
+        let caster = spawn_caster(&mut app, [hook, jab, uppercut]);
+
+        // Each ability is already active once it has run, so the next candidate is picked
+        for _ in 0..3 {
+            trigger(&mut app, TryActivateAbilityByParentTag{ entity: caster, parent: attack });
+        }
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![uppercut, hook, jab]);
+    }
+
+    #[test]
+    fn parent_tag_reports_the_last_failure_when_nothing_can_activate() {
+        let mut tags = TagRegistry::new();
+        let attack = tags.register("Ability.Attack");
+        let jab = tags.register("Ability.Attack.Jab");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(jab).with_stat_cost(StatCost::new(TestStats::Mana, 20.)));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| {
+            (e.ability, matches!(e.reason, ActivationFailureReason::InsufficientStat { .. }))
+        });
+        let caster = spawn_caster(&mut app, [jab]);
+
+        trigger(&mut app, TryActivateAbilityByParentTag{ entity: caster, parent: attack });
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, (TagId, bool)>(&app), vec![(attack, true)]);
+        assert_eq!(running(&app, caster), 0);
+    }
+}
@@ -0,0 +1,316 @@
+use bevy::{
+    ecs::system::SystemParam,
+    platform::collections::HashMap,
+    prelude::*,
+};
+use bevy_hierarchical_tags::prelude::*;
+use smallvec::SmallVec;
+use crate::targeting::TargetFilter;
+
+
+/// A volume to search for targets in, in world space
+#[derive(Clone, Copy, Debug)]
+pub enum AreaShape {
+    Sphere { center: Vec3, radius: f32 },
+    /// `half_angle` is in radians
+    Cone { origin: Vec3, direction: Vec3, range: f32, half_angle: f32 },
+    /// A line from `start` to `end` with thickness, e.g. for beams and charges
+    Capsule { start: Vec3, end: Vec3, radius: f32 },
+    Box { center: Vec3, half_extents: Vec3, rotation: Quat },
+}
+
+impl AreaShape {
+    pub fn contains(&self, point: Vec3) -> bool {
+        match *self {
+            Self::Sphere { center, radius } => center.distance_squared(point) <= radius * radius,
+            Self::Cone { origin, direction, range, half_angle } => {
+                let offset = point - origin;
+                let distance = offset.length();
+                distance <= range && (distance == 0. || direction.angle_between(offset) <= half_angle)
+            }
+            Self::Capsule { start, end, radius } => {
+                let line = end - start;
+                let t = (point - start).dot(line) / line.length_squared().max(f32::EPSILON);
+                let closest = start + line * t.clamp(0., 1.);
+                closest.distance_squared(point) <= radius * radius
+            }
+            Self::Box { center, half_extents, rotation } => {
+                let local = rotation.inverse() * (point - center);
+                local.abs().cmple(half_extents).all()
+            }
+        }
+    }
+
+    /// An axis aligned box around the shape, as (min, max)
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        match *self {
+            Self::Sphere { center, radius } => (center - radius, center + radius),
+            Self::Cone { origin, range, .. } => (origin - range, origin + range),
+            Self::Capsule { start, end, radius } => (start.min(end) - radius, start.max(end) + radius),
+            Self::Box { center, half_extents, rotation } => {
+                let matrix = Mat3::from_quat(rotation);
+                let extents = matrix.x_axis.abs() * half_extents.x
+                    + matrix.y_axis.abs() * half_extents.y
+                    + matrix.z_axis.abs() * half_extents.z;
+                (center - extents, center + extents)
+            }
+        }
+    }
+}
+
+/// A grid of entities with ActiveTags, so AbilityAreaQuery only has to check nearby ones.
+/// Insert it as a resource to opt in; the plugin keeps it up to date at the end of each frame.
+#[derive(Resource)]
+pub struct AbilitySpatialHash {
+    cell_size: f32,
+    cells: HashMap<IVec3, SmallVec<[Entity; 8]>>,
+    entities: HashMap<Entity, IVec3>,
+}
+
+impl AbilitySpatialHash {
+    /// `cell_size` should be around the size of your typical ability area
+    pub fn new(cell_size: f32) -> Self {
+        Self { cell_size: cell_size.max(f32::EPSILON), cells: HashMap::new(), entities: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    fn cell(&self, position: Vec3) -> IVec3 {
+        (position / self.cell_size).floor().as_ivec3()
+    }
+
+    fn insert(&mut self, entity: Entity, position: Vec3) {
+        let cell = self.cell(position);
+        match self.entities.insert(entity, cell) {
+            Some(old) if old == cell => return,
+            Some(old) => self.remove_from_cell(entity, old),
+            None => {}
+        }
+        self.cells.entry(cell).or_default().push(entity);
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        if let Some(cell) = self.entities.remove(&entity) {
+            self.remove_from_cell(entity, cell);
+        }
+    }
+
+    fn remove_from_cell(&mut self, entity: Entity, cell: IVec3) {
+        let Some(entities) = self.cells.get_mut(&cell) else { return };
+        entities.retain(|e| *e != entity);
+        if entities.is_empty() {
+            self.cells.remove(&cell);
+        }
+    }
+
+    /// Entities in every cell touching the box.  They still need to be checked against the shape.
+    pub fn candidates(&self, min: Vec3, max: Vec3) -> impl Iterator<Item = Entity> + '_ {
+        let (min, max) = (self.cell(min), self.cell(max));
+        (min.x..=max.x).flat_map(move |x| (min.y..=max.y).flat_map(move |y| {
+            (min.z..=max.z).map(move |z| IVec3::new(x, y, z))
+        }))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
+
+/// Entities leave the hash when they lose either ActiveTags or GlobalTransform
+#[allow(clippy::type_complexity)]
+pub(crate) fn update_spatial_hash(
+    mut hash: ResMut<AbilitySpatialHash>,
+    moved: Query<(Entity, &GlobalTransform), (With<ActiveTags>, Or<(Changed<GlobalTransform>, Added<ActiveTags>)>)>,
+    mut removed_tags: RemovedComponents<ActiveTags>,
+    mut removed_transforms: RemovedComponents<GlobalTransform>,
+) {
+    for entity in removed_tags.read().chain(removed_transforms.read()) {
+        hash.remove(entity);
+    }
+    for (entity, transform) in moved.iter() {
+        hash.insert(entity, transform.translation());
+    }
+}
+
+/// Finds entities with ActiveTags inside an AreaShape that pass a TargetFilter,
+/// e.g. everything with Team.Enemy and without State.Invulnerable within 4 units.
+/// Uses the AbilitySpatialHash if there is one.
+#[derive(SystemParam)]
+#[allow(clippy::type_complexity)]
+pub struct AbilityAreaQuery<'w, 's> {
+    targets: Query<'w, 's, (Entity, &'static GlobalTransform, &'static ActiveTags)>,
+    /// Entities the spatial hash may not have caught up with yet
+    changed: Query<'w, 's, Entity, (With<ActiveTags>, Or<(Changed<GlobalTransform>, Added<ActiveTags>)>)>,
+    tag_registry: Res<'w, TagRegistry>,
+    spatial_hash: Option<Res<'w, AbilitySpatialHash>>,
+}
+
+impl AbilityAreaQuery<'_, '_> {
+    pub fn get(&self, entity: Entity) -> Option<(&GlobalTransform, &ActiveTags)> {
+        self.targets.get(entity).ok().map(|(_, transform, tags)| (transform, tags))
+    }
+
+    pub fn matches(&self, entity: Entity, filter: &TargetFilter) -> bool {
+        self.get(entity).is_some_and(|(_, tags)| filter.matches(Some(tags), &self.tag_registry))
+    }
+
+    /// Every matching entity inside the shape.  Positions come from GlobalTransform, so an entity
+    /// moved with Transform this frame is found where it was after the last transform propagation.
+    /// The spatial hash is only rebuilt after propagation, so entities that changed since the
+    /// query last ran are checked as well as the nearby cells.
+    pub fn query(&self, shape: &AreaShape, filter: &TargetFilter) -> SmallVec<[Entity; 8]> {
+        let inside = |(_, transform, tags): (Entity, &GlobalTransform, &ActiveTags)| {
+            shape.contains(transform.translation()) && filter.matches(Some(tags), &self.tag_registry)
+        };
+        match &self.spatial_hash {
+            Some(hash) => {
+                let (min, max) = shape.bounds();
+                let mut found: SmallVec<[Entity; 8]> = hash.candidates(min, max)
+                    .chain(self.changed.iter())
+                    .filter(|entity| self.targets.get(*entity).is_ok_and(inside))
+                    .collect();
+                found.sort_unstable();
+                found.dedup();
+                found
+            }
+            None => self.targets.iter()
+                .filter(|target| inside(*target))
+                .map(|(entity, ..)| entity)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_4;
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    #[test]
+    fn cones_contain_their_apex_and_stop_at_the_half_angle() {
+        let cone = AreaShape::Cone { origin: Vec3::ONE, direction: Vec3::X, range: 5., half_angle: FRAC_PI_4 };
+        assert!(cone.contains(Vec3::ONE));
+        assert!(cone.contains(Vec3::ONE + Vec3::new(3., 2.9, 0.)));
+        assert!(!cone.contains(Vec3::ONE + Vec3::new(3., 3.1, 0.)));
+        assert!(!cone.contains(Vec3::ONE + Vec3::X * 5.1));
+        assert!(!cone.contains(Vec3::ONE - Vec3::X));
+    }
+
+    #[test]
+    fn zero_length_capsules_are_spheres() {
+        let capsule = AreaShape::Capsule { start: Vec3::Y, end: Vec3::Y, radius: 1. };
+        assert!(capsule.contains(Vec3::Y));
+        assert!(capsule.contains(Vec3::new(0., 1., 0.99)));
+        assert!(!capsule.contains(Vec3::new(0., 1., 1.01)));
+        assert_eq!(capsule.bounds(), (Vec3::new(-1., 0., -1.), Vec3::new(1., 2., 1.)));
+    }
+
+    #[test]
+    fn rotated_boxes_use_their_local_axes() {
+        let rotation = Quat::from_rotation_y(FRAC_PI_4);
+        let shape = AreaShape::Box { center: Vec3::ZERO, half_extents: Vec3::new(2., 1., 0.5), rotation };
+        let local_x = rotation * Vec3::X;
+        assert!(shape.contains(local_x * 1.9));
+        assert!(!shape.contains(local_x * 2.1));
+        // Inside the unrotated box but outside the rotated one
+        assert!(!shape.contains(Vec3::X * 1.5));
+
+        let (min, max) = shape.bounds();
+        for corner in [Vec3::new(2., 1., 0.5), Vec3::new(-2., -1., 0.5), Vec3::new(2., 1., -0.5)] {
+            let point = rotation * corner;
+            assert!(point.cmpge(min - 0.001).all() && point.cmple(max + 0.001).all());
+        }
+    }
+
+    #[test]
+    fn candidates_span_every_cell_the_bounds_touch() {
+        let mut world = World::new();
+        let [a, b, c] = [(); 3].map(|_| world.spawn_empty().id());
+        let mut hash = AbilitySpatialHash::new(1.);
+        hash.insert(a, Vec3::new(0.9, 0., 0.));
+        hash.insert(b, Vec3::new(1.1, 0., 0.));
+        hash.insert(c, Vec3::new(3.5, 0., 0.));
+
+        let mut found: Vec<Entity> = hash.candidates(Vec3::new(0.5, -0.5, -0.5), Vec3::new(1.5, 0.5, 0.5)).collect();
+        found.sort();
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(hash.candidates(Vec3::splat(-0.5), Vec3::splat(-0.1)).count(), 0);
+    }
+
+    fn hash_app() -> App {
+        let mut app = app(TagRegistry::new(), AbilitiesPlugin::<TestStats>::new());
+        app.insert_resource(AbilitySpatialHash::new(1.));
+        app
+    }
+
+    fn cell(app: &App, entity: Entity) -> Option<IVec3> {
+        app.world().resource::<AbilitySpatialHash>().entities.get(&entity).copied()
+    }
+
+    #[test]
+    fn moved_entities_change_cells() {
+        let mut app = hash_app();
+        let entity = app.world_mut().spawn((ActiveTags::default(), GlobalTransform::from_translation(Vec3::splat(0.5)))).id();
+        update(&mut app);
+        assert_eq!(cell(&app, entity), Some(IVec3::ZERO));
+
+        *app.world_mut().get_mut::<GlobalTransform>(entity).unwrap() = GlobalTransform::from_translation(Vec3::new(2.5, 0.5, 0.5));
+        update(&mut app);
+        let hash = app.world().resource::<AbilitySpatialHash>();
+        assert_eq!(cell(&app, entity), Some(IVec3::new(2, 0, 0)));
+        assert!(!hash.cells.contains_key(&IVec3::ZERO));
+        assert_eq!(hash.len(), 1);
+    }
+
+    #[test]
+    fn entities_leave_the_hash_when_despawned_or_untransformed() {
+        let mut app = hash_app();
+        let despawned = app.world_mut().spawn((ActiveTags::default(), GlobalTransform::default())).id();
+        let untransformed = app.world_mut().spawn((ActiveTags::default(), GlobalTransform::default())).id();
+        let untagged = app.world_mut().spawn((ActiveTags::default(), GlobalTransform::default())).id();
+        update(&mut app);
+        assert_eq!(app.world().resource::<AbilitySpatialHash>().len(), 3);
+
+        app.world_mut().despawn(despawned);
+        app.world_mut().entity_mut(untransformed).remove::<GlobalTransform>();
+        app.world_mut().entity_mut(untagged).remove::<ActiveTags>();
+        update(&mut app);
+        assert!(app.world().resource::<AbilitySpatialHash>().is_empty());
+        assert!(app.world().resource::<AbilitySpatialHash>().cells.is_empty());
+    }
+
+    #[derive(Resource, Default)]
+    struct Found(Vec<Entity>);
+
+    #[test]
+    fn queries_find_entities_spawned_or_moved_before_the_hash_updates() {
+        let mut app = hash_app();
+        app.init_resource::<Found>();
+        app.add_systems(Update, |area: AbilityAreaQuery, mut found: ResMut<Found>| {
+            let shape = AreaShape::Sphere { center: Vec3::ZERO, radius: 1. };
+            found.0 = area.query(&shape, &TargetFilter::new()).to_vec();
+        });
+        let moved = app.world_mut().spawn((ActiveTags::default(), GlobalTransform::from_translation(Vec3::X * 10.))).id();
+        update(&mut app);
+        assert!(app.world().resource::<Found>().0.is_empty());
+
+        let spawned = app.world_mut().spawn((ActiveTags::default(), GlobalTransform::default())).id();
+        update(&mut app);
+        assert_eq!(app.world().resource::<Found>().0, vec![spawned]);
+
+        *app.world_mut().get_mut::<GlobalTransform>(moved).unwrap() = GlobalTransform::from_translation(Vec3::Y * 0.5);
+        update(&mut app);
+        let mut expected = vec![spawned, moved];
+        expected.sort();
+        assert_eq!(app.world().resource::<Found>().0, expected);
+    }
+}
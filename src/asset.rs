@@ -0,0 +1,557 @@
+use std::{collections::BTreeMap, marker::PhantomData, str::FromStr, sync::{Arc, RwLock}};
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext},
+    platform::collections::HashMap,
+    prelude::*,
+};
+use bevy_behave::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use serde::Deserialize;
+use smallvec::SmallVec;
+use crate::{
+    costs::{CostPolicy, ItemCost, StatCost},
+    levels::ScalableValue,
+    prelude::*,
+};
+
+/// Loads ability definitions from `.ability.ron` and `.ability.json` files.
+///
+/// Stats are looked up by variant name with FromStr, so your stat enum needs to implement it.
+/// Execution trees can't be written in the file, so register them by name here
+/// and reference that name from the file.
+pub struct AbilityAssetPlugin<T: StatTrait + FromStr> {
+    trees: AbilityTrees,
+    paths: Vec<String>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: StatTrait + FromStr> Default for AbilityAssetPlugin<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: StatTrait + FromStr> AbilityAssetPlugin<T> {
+    pub fn new() -> Self {
+        Self { trees: AbilityTrees::default(), paths: Vec::new(), _marker: PhantomData }
+    }
+
+    /// Register an execution tree that ability files can refer to by name
+    pub fn with_tree(mut self, name: impl Into<String>, tree: Tree<Behave>) -> Self {
+        self.trees.insert(name.into(), tree);
+        self
+    }
+
+    /// Load this ability file on startup
+    pub fn load(mut self, path: impl Into<String>) -> Self {
+        self.paths.push(path.into());
+        self
+    }
+}
+
+impl<T: StatTrait + FromStr> Plugin for AbilityAssetPlugin<T> {
+    fn build(&self, app: &mut App) {
+        let loader_tags = LoaderTags::default();
+        app.init_asset::<AbilityAsset>();
+        app.register_asset_loader(AbilityAssetLoader::<T> {
+            tags: loader_tags.clone(),
+            trees: self.trees.clone(),
+            _marker: PhantomData,
+        });
+        app.insert_resource(loader_tags);
+        app.insert_resource(self.trees.clone());
+        app.insert_resource(AbilityAssetPaths(self.paths.clone()));
+        app.init_resource::<AbilityAssetHandles>();
+        app.init_resource::<AbilityAssetIndex>();
+        // Load after Startup so tags registered by Startup systems are known to the loader
+        app.add_systems(PostStartup, (sync_loader_tags, load_ability_assets).chain());
+        app.add_systems(PreUpdate, (
+            sync_loader_tags.run_if(resource_changed::<TagRegistry>),
+            register_loaded_abilities::<T>,
+        ));
+    }
+}
+
+/// Execution trees that ability files can reference by name
+#[derive(Resource, Deref, DerefMut, Clone, Default)]
+pub struct AbilityTrees(HashMap<String, Tree<Behave>>);
+
+#[derive(Resource)]
+struct AbilityAssetPaths(Vec<String>);
+
+/// Keeps the ability files loaded by AbilityAssetPlugin alive
+#[derive(Resource, Default, Deref)]
+pub struct AbilityAssetHandles(Vec<Handle<AbilityAsset>>);
+
+/// A number in an ability file that can scale with the ability level
+#[derive(Deserialize, Clone)]
+pub enum ScalableValueAsset {
+    Fixed(f32),
+    Table(Vec<f32>),
+    Linear { base: f32, per_level: f32 },
+}
+
+impl From<&ScalableValueAsset> for ScalableValue {
+    fn from(value: &ScalableValueAsset) -> Self {
+        match value {
+            ScalableValueAsset::Fixed(v) => ScalableValue::Fixed(*v),
+            ScalableValueAsset::Table(v) => ScalableValue::table(v.iter().copied()),
+            ScalableValueAsset::Linear { base, per_level } => ScalableValue::Linear {
+                base: *base, per_level: *per_level
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StatCostAsset {
+    pub stat: String,
+    pub amount: ScalableValueAsset,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ItemCostAsset {
+    pub item_id: u16,
+    pub amount: u8,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ChargesAsset {
+    pub max: u8,
+    pub recharge_time: f32,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum CostPolicyAsset {
+    #[default]
+    Never,
+    OnActivate,
+    OnCommit,
+}
+
+impl From<CostPolicyAsset> for CostPolicy {
+    fn from(value: CostPolicyAsset) -> Self {
+        match value {
+            CostPolicyAsset::Never => CostPolicy::Never,
+            CostPolicyAsset::OnActivate => CostPolicy::OnActivate,
+            CostPolicyAsset::OnCommit => CostPolicy::OnCommit,
+        }
+    }
+}
+
+fn interruptible_default() -> bool { true }
+
+#[derive(Deserialize, Clone)]
+pub struct CastTimeAsset {
+    pub duration: ScalableValueAsset,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "interruptible_default")]
+    pub interruptible: bool,
+    #[serde(default)]
+    pub canceled_by_movement: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum ActivationPolicyAsset {
+    #[default]
+    Standard,
+    Passive,
+    Toggle,
+    Channeled { duration: f32, tick_interval: f32 },
+}
+
+impl From<ActivationPolicyAsset> for ActivationPolicy {
+    fn from(value: ActivationPolicyAsset) -> Self {
+        match value {
+            ActivationPolicyAsset::Standard => ActivationPolicy::Standard,
+            ActivationPolicyAsset::Passive => ActivationPolicy::Passive,
+            ActivationPolicyAsset::Toggle => ActivationPolicy::Toggle,
+            ActivationPolicyAsset::Channeled { duration, tick_interval } => {
+                ActivationPolicy::Channeled { duration, tick_interval }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub enum AbilityTriggerAsset {
+    TagAdded(String),
+    TagRemoved(String),
+    GameplayEvent(String),
+}
+
+/// An ability definition as written in a file.
+/// Tags, stats and trees are referenced by name and resolved once the file is loaded.
+#[derive(Asset, TypePath, Deserialize, Clone)]
+pub struct AbilityAsset {
+    pub tag: String,
+    #[serde(default)]
+    pub required: Vec<String>,
+    #[serde(default)]
+    pub blocked_by: Vec<String>,
+    #[serde(default)]
+    pub canceled_by: Vec<String>,
+    #[serde(default)]
+    pub adds_tags: Vec<String>,
+    #[serde(default)]
+    pub stat_costs: Vec<StatCostAsset>,
+    #[serde(default)]
+    pub item_costs: Vec<ItemCostAsset>,
+    /// Stat costs paid every second while the ability runs
+    #[serde(default)]
+    pub stat_drains: Vec<StatCostAsset>,
+    /// Stat costs paid on every tick of a channeled ability
+    #[serde(default)]
+    pub tick_costs: Vec<StatCostAsset>,
+    #[serde(default)]
+    pub cost_policy: CostPolicyAsset,
+    #[serde(default)]
+    pub cooldown: Option<ScalableValueAsset>,
+    #[serde(default)]
+    pub cooldown_tag: Option<String>,
+    #[serde(default)]
+    pub charges: Option<ChargesAsset>,
+    #[serde(default)]
+    pub cast_time: Option<CastTimeAsset>,
+    #[serde(default)]
+    pub input_priority: u8,
+    #[serde(default)]
+    pub magnitudes: BTreeMap<String, ScalableValueAsset>,
+    #[serde(default)]
+    pub activation: ActivationPolicyAsset,
+    #[serde(default)]
+    pub triggers: Vec<AbilityTriggerAsset>,
+    #[serde(default)]
+    pub execution_tree: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum AbilityAssetError {
+    Io(std::io::Error),
+    #[cfg(feature = "ron")]
+    Ron(ron::error::SpannedError),
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    UnsupportedExtension(String),
+    UnknownTag(String),
+    UnknownStat(String),
+    UnknownTree(String),
+}
+
+impl std::fmt::Display for AbilityAssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read ability file: {e}"),
+            #[cfg(feature = "ron")]
+            Self::Ron(e) => write!(f, "could not parse ability file: {e}"),
+            #[cfg(feature = "json")]
+            Self::Json(e) => write!(f, "could not parse ability file: {e}"),
+            Self::UnsupportedExtension(ext) => write!(f, "unsupported ability file extension \"{ext}\""),
+            Self::UnknownTag(tag) => write!(f, "unknown tag \"{tag}\", register it in the TagRegistry first"),
+            Self::UnknownStat(stat) => write!(f, "unknown stat \"{stat}\""),
+            Self::UnknownTree(tree) => write!(f, "unknown execution tree \"{tree}\", register it with AbilityAssetPlugin::with_tree"),
+        }
+    }
+}
+
+impl std::error::Error for AbilityAssetError {}
+
+impl From<std::io::Error> for AbilityAssetError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl AbilityAsset {
+    /// Turn the file contents into an AbilityDefinition, looking up everything referenced by name
+    pub fn resolve<T: StatTrait + FromStr>(
+        &self,
+        tag_registry: &TagRegistry,
+        trees: &AbilityTrees,
+    ) -> Result<AbilityDefinition<T>, AbilityAssetError> {
+        let tag = |name: &String| {
+            tag_registry.get_id(name).ok_or_else(|| AbilityAssetError::UnknownTag(name.clone()))
+        };
+        let tags = |names: &Vec<String>| names.iter().map(tag).collect::<Result<SmallVec<[TagId; 4]>, _>>();
+
+        let mut definition = AbilityDefinition::<T>::new(tag(&self.tag)?)
+            .required(tags(&self.required)?)
+            .blocked_by(tags(&self.blocked_by)?)
+            .canceled_by(tags(&self.canceled_by)?)
+            .adds_tags(tags(&self.adds_tags)?)
+            .with_cost_policy(self.cost_policy.into())
+            .with_activation_policy(self.activation.into())
+            .with_input_priority(self.input_priority);
+
+        for cost in self.stat_costs.iter() {
+            let stat = T::from_str(&cost.stat)
+                .map_err(|_| AbilityAssetError::UnknownStat(cost.stat.clone()))?;
+            definition = definition.with_stat_cost(StatCost::new(stat, ScalableValue::from(&cost.amount)));
+        }
+        for drain in self.stat_drains.iter() {
+            let stat = T::from_str(&drain.stat)
+                .map_err(|_| AbilityAssetError::UnknownStat(drain.stat.clone()))?;
+            definition = definition.with_stat_drain(StatCost::new(stat, ScalableValue::from(&drain.amount)));
+        }
+        for cost in self.tick_costs.iter() {
+            let stat = T::from_str(&cost.stat)
+                .map_err(|_| AbilityAssetError::UnknownStat(cost.stat.clone()))?;
+            definition = definition.with_tick_cost(StatCost::new(stat, ScalableValue::from(&cost.amount)));
+        }
+        for cost in self.item_costs.iter() {
+            definition = definition.with_item_cost(ItemCost { item_id: cost.item_id, amount: cost.amount });
+        }
+        if let Some(cooldown) = &self.cooldown {
+            definition = definition.with_cooldown(ScalableValue::from(cooldown));
+        }
+        if let Some(cooldown_tag) = &self.cooldown_tag {
+            definition = definition.with_cooldown_tag(tag(cooldown_tag)?);
+        }
+        if let Some(charges) = &self.charges {
+            definition = definition.with_charges(charges.max, charges.recharge_time);
+        }
+        if let Some(cast) = &self.cast_time {
+            let mut cast_time = AbilityCastTime::new(ScalableValue::from(&cast.duration))
+                .with_tags(tags(&cast.tags)?);
+            cast_time.interruptible = cast.interruptible;
+            cast_time.canceled_by_movement = cast.canceled_by_movement;
+            definition = definition.with_cast_time(cast_time);
+        }
+        for (name, value) in self.magnitudes.iter() {
+            definition = definition.with_magnitude(name.clone(), ScalableValue::from(value));
+        }
+        for trigger in self.triggers.iter() {
+            definition = definition.triggered_by(match trigger {
+                AbilityTriggerAsset::TagAdded(name) => AbilityTrigger::TagAdded(tag(name)?),
+                AbilityTriggerAsset::TagRemoved(name) => AbilityTrigger::TagRemoved(tag(name)?),
+                AbilityTriggerAsset::GameplayEvent(name) => AbilityTrigger::GameplayEvent(tag(name)?),
+            });
+        }
+        if let Some(tree) = &self.execution_tree {
+            let Some(tree) = trees.get(tree) else {
+                return Err(AbilityAssetError::UnknownTree(tree.clone()));
+            };
+            definition = definition.with_execution_tree(tree.clone());
+        }
+        Ok(definition)
+    }
+}
+
+#[cfg(all(feature = "ron", feature = "json"))]
+const EXTENSIONS: &[&str] = &["ability.ron", "ability.json"];
+#[cfg(all(feature = "ron", not(feature = "json")))]
+const EXTENSIONS: &[&str] = &["ability.ron"];
+#[cfg(all(feature = "json", not(feature = "ron")))]
+const EXTENSIONS: &[&str] = &["ability.json"];
+
+/// A copy of the TagRegistry for the loader, which runs off the main world
+#[derive(Resource, Clone, Default)]
+struct LoaderTags(Arc<RwLock<TagRegistry>>);
+
+fn sync_loader_tags(tag_registry: Option<Res<TagRegistry>>, loader_tags: Res<LoaderTags>) {
+    let Some(tag_registry) = tag_registry else { return };
+    if let Ok(mut tags) = loader_tags.0.write() {
+        *tags = tag_registry.clone();
+    }
+}
+
+/// Files that reference unknown tags, stats or trees fail to load with an AbilityAssetError
+pub struct AbilityAssetLoader<T: StatTrait + FromStr> {
+    tags: LoaderTags,
+    trees: AbilityTrees,
+    _marker: PhantomData<T>,
+}
+
+impl<T: StatTrait + FromStr> AssetLoader for AbilityAssetLoader<T> {
+    type Asset = AbilityAsset;
+    type Settings = ();
+    type Error = AbilityAssetError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let extension = load_context.path()
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        let asset: AbilityAsset = match extension {
+            #[cfg(feature = "ron")]
+            "ron" => ron::de::from_bytes(&bytes).map_err(AbilityAssetError::Ron)?,
+            #[cfg(feature = "json")]
+            "json" => serde_json::from_slice(&bytes).map_err(AbilityAssetError::Json)?,
+            other => return Err(AbilityAssetError::UnsupportedExtension(other.to_string())),
+        };
+        // Check every name now so a bad file fails to load instead of being skipped later
+        let tags = self.tags.0.read().unwrap_or_else(|e| e.into_inner());
+        asset.resolve::<T>(&tags, &self.trees)?;
+        Ok(asset)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        EXTENSIONS
+    }
+}
+
+fn load_ability_assets(
+    paths: Res<AbilityAssetPaths>,
+    asset_server: Res<AssetServer>,
+    mut handles: ResMut<AbilityAssetHandles>,
+) {
+    for path in paths.0.iter() {
+        handles.0.push(asset_server.load(path.clone()));
+    }
+}
+
+/// Which ability tag each loaded file defines, so reloads can find what to replace
+#[derive(Resource, Default)]
+pub(crate) struct AbilityAssetIndex(HashMap<AssetId<AbilityAsset>, TagId>);
+
+/// Adds abilities to the AbilityRegistry as their files finish loading, and replaces
+/// them when the files change.  GrantedAbilities read from the registry so they pick up
+/// the change, but abilities that are already running keep the definition they started with.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn register_loaded_abilities<T: StatTrait + FromStr>(
+    mut events: EventReader<AssetEvent<AbilityAsset>>,
+    assets: Res<Assets<AbilityAsset>>,
+    asset_server: Res<AssetServer>,
+    tag_registry: Res<TagRegistry>,
+    trees: Res<AbilityTrees>,
+    mut registry: ResMut<AbilityRegistry<T>>,
+    mut index: ResMut<AbilityAssetIndex>,
+    mut granted: Query<&mut GrantedAbilities<T>>,
+) {
+    for event in events.read() {
+        // Sent on the first load and again on every reload.  A reload also sends Modified,
+        // so only this one is handled to process each change once.
+        let AssetEvent::LoadedWithDependencies { id } = event else { continue };
+        let Some(asset) = assets.get(*id) else { continue };
+        let definition = match asset.resolve::<T>(&tag_registry, &trees) {
+            Ok(definition) => definition,
+            Err(err) => {
+                let path = asset_server.get_path(*id)
+                    .map(|p| p.to_string())
+                    .unwrap_or_else(|| "<unknown>".to_string());
+                error!("Failed to load ability {path}: {err}");
+                continue;
+            }
+        };
+
+        let tag = definition.tags.ability;
+        if let Some(old_tag) = index.0.insert(*id, tag).filter(|old_tag| *old_tag != tag) {
+            // The file now defines a different ability, so move the grants over to it
+            registry.remove(&old_tag);
+            for mut granted in granted.iter_mut().filter(|g| g.contains(old_tag)) {
+                granted.rename(old_tag, tag);
+            }
+        }
+
+        registry.insert(tag, definition);
+    }
+}
+
+#[cfg(all(test, feature = "ron"))]
+mod tests {
+    use bevy_behave::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use bevy::prelude::*;
+    use crate::{prelude::*, testing::*};
+    use super::AbilityAsset;
+
+    fn parse(ron: &str) -> AbilityAsset {
+        ron::from_str(ron).unwrap()
+    }
+
+    fn registry() -> TagRegistry {
+        let mut tags = TagRegistry::new();
+        tags.register("Ability.Stun");
+        tags.register("Character.Movement.Blocked.Casting");
+        tags.register("Cooldown.Stun");
+        tags
+    }
+
+    #[test]
+    fn resolve_builds_the_definition() {
+        let tags = registry();
+        let mut trees = AbilityTrees::default();
+        trees.insert("stun".to_string(), tree! { Behave::AlwaysSucceed });
+        let asset = parse(r#"(
+            tag: "Ability.Stun",
+            blocked_by: ["Character.Movement.Blocked.Casting"],
+            stat_costs: [(stat: "Mana", amount: Table([25.0, 30.0]))],
+            cost_policy: OnActivate,
+            cooldown: Some(Fixed(5.0)),
+            cooldown_tag: Some("Cooldown.Stun"),
+            charges: Some((max: 2, recharge_time: 3.0)),
+            magnitudes: { "radius": Linear(base: 4.0, per_level: 0.5) },
+            activation: Toggle,
+            execution_tree: Some("stun"),
+        )"#);
+        let definition = asset.resolve::<TestStats>(&tags, &trees).unwrap();
+
+        assert_eq!(definition.tags.ability, tags.get_id("Ability.Stun").unwrap());
+        assert_eq!(definition.tags.blocked_by.len(), 1);
+        assert_eq!(definition.costs.stat_costs[0].stat, TestStats::Mana);
+        assert_eq!(definition.costs.stat_costs[0].amount.at(2), 30.);
+        assert_eq!(definition.costs.policy, CostPolicy::OnActivate);
+        let cooldown = definition.cooldown.as_ref().unwrap();
+        assert_eq!((cooldown.duration.at(1), cooldown.tag), (5., tags.get_id("Cooldown.Stun")));
+        assert_eq!(definition.charges.as_ref().map(|c| c.max), Some(2));
+        assert_eq!(definition.magnitudes["radius"].at(3), 5.);
+        assert_eq!(definition.activation, ActivationPolicy::Toggle);
+        assert!(definition.execution_tree.is_some());
+    }
+
+    #[test]
+    fn resolve_rejects_unknown_names() {
+        let tags = registry();
+        let trees = AbilityTrees::default();
+        let unknown_tag = parse(r#"(tag: "Ability.Stun", required: ["Character.State.Flying"])"#);
+        let unknown_stat = parse(r#"(tag: "Ability.Stun", stat_costs: [(stat: "Rage", amount: Fixed(1.0))])"#);
+        let unknown_tree = parse(r#"(tag: "Ability.Stun", execution_tree: Some("stun"))"#);
+
+        assert!(matches!(
+            unknown_tag.resolve::<TestStats>(&tags, &trees),
+            Err(AbilityAssetError::UnknownTag(tag)) if tag == "Character.State.Flying"
+        ));
+        assert!(matches!(
+            unknown_stat.resolve::<TestStats>(&tags, &trees),
+            Err(AbilityAssetError::UnknownStat(stat)) if stat == "Rage"
+        ));
+        assert!(matches!(
+            unknown_tree.resolve::<TestStats>(&tags, &trees),
+            Err(AbilityAssetError::UnknownTree(tree)) if tree == "stun"
+        ));
+    }
+
+    #[test]
+    fn reloading_a_renamed_ability_moves_its_grants() {
+        let mut tags = registry();
+        let stun = tags.get_id("Ability.Stun").unwrap();
+        let daze = tags.register("Ability.Daze");
+        let mut app = app(tags, AbilitiesPlugin::<TestStats>::new());
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default(), AbilityAssetPlugin::<TestStats>::new()));
+        let caster = spawn_caster(&mut app, [stun]);
+
+        let handle = app.world_mut().resource_mut::<Assets<AbilityAsset>>()
+            .add(parse(r#"(tag: "Ability.Stun", cooldown: Some(Fixed(5.0)))"#));
+        app.world_mut().send_event(AssetEvent::LoadedWithDependencies { id: handle.id() });
+        update(&mut app);
+        assert!(app.world().resource::<AbilityRegistry<TestStats>>().contains_key(&stun));
+
+        app.world_mut().resource_mut::<Assets<AbilityAsset>>().get_mut(&handle).unwrap().tag = "Ability.Daze".to_string();
+        app.world_mut().send_event(AssetEvent::LoadedWithDependencies { id: handle.id() });
+        update(&mut app);
+
+        let registry = app.world().resource::<AbilityRegistry<TestStats>>();
+        assert!(!registry.contains_key(&stun) && registry.contains_key(&daze));
+        let granted = app.world().get::<GrantedAbilities<TestStats>>(caster).unwrap();
+        assert!(!granted.contains(stun) && granted.contains(daze));
+    }
+}
@@ -0,0 +1,271 @@
+use std::cmp::Reverse;
+use bevy::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::SmallVec;
+use crate::{
+    ability::{ability_tags_ok, AbilityActivation},
+    prelude::*,
+};
+
+
+struct BufferedActivation {
+    ability: TagId,
+    priority: u8,
+    sequence: u32,
+    timer: Timer,
+    target_data: TargetData,
+}
+
+/// Holds activations that were rejected only because of blocked_by or canceled_by tags,
+/// or because the ability was still running, and retries them once they can activate,
+/// e.g. pressing attack just before a dodge ends.
+/// Add it to an entity to opt in.
+#[derive(Component)]
+pub struct AbilityInputBuffer {
+    /// Seconds an activation is held before it is dropped
+    pub window: f32,
+    /// How many activations can be held at once
+    pub max_depth: usize,
+    entries: SmallVec<[BufferedActivation; 4]>,
+    next_sequence: u32,
+}
+
+impl AbilityInputBuffer {
+    /// A buffer that holds a single activation for `window` seconds
+    pub fn new(window: f32) -> Self {
+        Self { window, max_depth: 1, entries: SmallVec::new(), next_sequence: 0 }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, ability: TagId) -> bool {
+        self.entries.iter().any(|e| e.ability == ability)
+    }
+
+    /// Drop everything that is buffered, e.g. when the character is stunned
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Buffer an activation.  Pressing the same ability again restarts its window.
+    /// When the buffer is full the lowest priority entry is replaced if the new one
+    /// has the same or higher priority.  Returns false if the activation was dropped.
+    pub(crate) fn push(&mut self, ability: TagId, priority: u8, target_data: TargetData) -> bool {
+        let sequence = self.next_sequence;
+        let entry = BufferedActivation {
+            ability,
+            priority,
+            sequence,
+            timer: Timer::from_seconds(self.window, TimerMode::Once),
+            target_data,
+        };
+        if let Some(index) = self.entries.iter().position(|e| e.ability == ability) {
+            self.entries[index] = entry;
+        } else if self.entries.len() < self.max_depth {
+            self.entries.push(entry);
+        } else {
+            // Replace the lowest priority entry, the oldest one if there is a tie
+            let lowest = self.entries.iter()
+                .enumerate()
+                .min_by_key(|(_, e)| (e.priority, e.sequence))
+                .map(|(i, _)| i);
+            match lowest {
+                Some(index) if self.entries[index].priority <= priority => self.entries[index] = entry,
+                _ => return false,
+            }
+        }
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        true
+    }
+}
+
+/// Expires old buffered activations and retries the best one that can activate now.
+/// Higher priority goes first, then whichever was pressed first.  Only one is started per frame.
+pub(crate) fn retry_buffered_activations<T: StatTrait>(
+    mut q: Query<(Entity, &mut AbilityInputBuffer)>,
+    mut activation: AbilityActivation<T>,
+    registry: Res<AbilityRegistry<T>>,
+    tag_registry: Res<TagRegistry>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, mut buffer) in q.iter_mut() {
+        if buffer.entries.is_empty() { continue }
+        buffer.entries.retain(|e| {
+            e.timer.tick(time.delta());
+            if !e.timer.finished() { return true }
+            commands.trigger(BufferedActivationExpired{ entity, ability: e.ability });
+            false
+        });
+
+        let mut order: SmallVec<[usize; 4]> = (0..buffer.entries.len()).collect();
+        order.sort_by_key(|i| (Reverse(buffer.entries[*i].priority), buffer.entries[*i].sequence));
+        for index in order {
+            let entry = &buffer.entries[index];
+            let Some(definition) = registry.get(&entry.ability) else { continue };
+            // Skip entries that are still waiting before building the ability for the full check
+            let waiting = activation.caster(entity).is_none_or(|(tags, _, active)| {
+                (definition.activation != ActivationPolicy::Toggle && active.is_active(entry.ability))
+                    || ability_tags_ok(&definition.tags, &tag_registry, tags).is_err()
+            });
+            if waiting { continue }
+            let ability = Ability::from(definition).with_target_data(entry.target_data.clone());
+            if activation.try_execute(entity, ability, &mut commands).is_ok() {
+                buffer.entries.remove(index);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_gameplay_effects::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+    use super::AbilityInputBuffer;
+
+    struct Attack {
+        attack: TagId,
+        dodging: TagId,
+    }
+
+    fn attack(cost: f32) -> (Attack, App, Entity) {
+        let mut tags = TagRegistry::new();
+        let attack = tags.register("Ability.Attack");
+        let dodging = tags.register("Character.State.Dodging");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(attack)
+            .blocked_by([dodging])
+            .with_stat_cost(StatCost::new(TestStats::Mana, cost))
+            .with_cost_policy(CostPolicy::OnActivate)
+        );
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &ExecuteAbility<TestStats>| e.ability.tags.ability);
+        record(&mut app, |e: &AbilityActivationBuffered| e.ability);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| e.ability);
+        let caster = spawn_caster(&mut app, [attack]);
+        app.world_mut().entity_mut(caster).insert(AbilityInputBuffer::new(0.5));
+        (Attack { attack, dodging }, app, caster)
+    }
+
+    fn spend_mana(app: &mut App, entity: Entity, amount: f32) {
+        trigger(app, AddEffect(AddEffectData::<TestStats>::new(
+            entity,
+            GameplayEffect::new(
+                None,
+                TestStats::Mana,
+                EffectMagnitude::Fixed(-amount),
+                EffectCalculation::Additive,
+                EffectDuration::Immediate,
+            ),
+            None,
+        )));
+    }
+
+    #[test]
+    fn blocked_activations_run_once_the_tags_clear() {
+        let (tags, mut app, caster) = attack(2.);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(tags.dodging);
+
+        try_execute(&mut app, caster, tags.attack);
+        update(&mut app);
+        assert_eq!(recorded::<AbilityActivationBuffered, TagId>(&app), vec![tags.attack]);
+        assert!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).is_empty());
+
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(tags.dodging);
+        tick(&mut app, 0.1);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![tags.attack]);
+        assert!(app.world().get::<AbilityInputBuffer>(caster).unwrap().is_empty());
+        assert!(recorded::<AbilityActivationFailed<TestStats>, TagId>(&app).is_empty());
+    }
+
+    #[test]
+    fn activations_that_fail_other_checks_are_not_buffered() {
+        let (tags, mut app, caster) = attack(20.);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(tags.dodging);
+
+        try_execute(&mut app, caster, tags.attack);
+        assert!(recorded::<AbilityActivationBuffered, TagId>(&app).is_empty());
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, TagId>(&app), vec![tags.attack]);
+    }
+
+    #[test]
+    fn activations_of_a_running_ability_wait_for_it_to_end() {
+        let (tags, mut app, caster) = attack(2.);
+
+        try_execute(&mut app, caster, tags.attack);
+        try_execute(&mut app, caster, tags.attack);
+        assert_eq!(recorded::<AbilityActivationBuffered, TagId>(&app), vec![tags.attack]);
+        tick(&mut app, 0.1);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).len(), 1);
+
+        trigger(&mut app, CancelAbility { entity: caster, ability: tags.attack });
+        tick(&mut app, 0.1);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).len(), 2);
+    }
+
+    #[test]
+    fn running_abilities_missing_required_tags_are_not_buffered() {
+        let mut tags = TagRegistry::new();
+        let aim = tags.register("Ability.Aim");
+        let armed = tags.register("Character.State.Armed");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(aim).required([armed]));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationBuffered| e.ability);
+        let caster = spawn_caster(&mut app, [aim]);
+        app.world_mut().entity_mut(caster).insert(AbilityInputBuffer::new(0.5));
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(armed);
+
+        try_execute(&mut app, caster, aim);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(armed);
+        try_execute(&mut app, caster, aim);
+        assert!(recorded::<AbilityActivationBuffered, TagId>(&app).is_empty());
+    }
+
+    #[test]
+    fn retries_run_every_check_and_expire() {
+        let (tags, mut app, caster) = attack(6.);
+        record(&mut app, |e: &BufferedActivationExpired| e.ability);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(tags.dodging);
+
+        try_execute(&mut app, caster, tags.attack);
+        spend_mana(&mut app, caster, 6.);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(tags.dodging);
+        tick(&mut app, 0.1);
+        assert!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).is_empty());
+        assert!(app.world().get::<AbilityInputBuffer>(caster).unwrap().contains(tags.attack));
+
+        tick(&mut app, 0.5);
+        assert_eq!(recorded::<BufferedActivationExpired, TagId>(&app), vec![tags.attack]);
+        assert!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).is_empty());
+    }
+
+    #[test]
+    fn full_buffers_keep_the_highest_priority() {
+        let mut tags = TagRegistry::new();
+        let [jab, hook, kick] = ["Ability.Jab", "Ability.Hook", "Ability.Kick"].map(|t| tags.register(t));
+        let mut buffer = AbilityInputBuffer::new(1.).with_max_depth(2);
+
+        assert!(buffer.push(jab, 2, TargetData::None));
+        assert!(buffer.push(hook, 1, TargetData::None));
+        assert!(!buffer.push(kick, 0, TargetData::None));
+        assert!(buffer.push(kick, 1, TargetData::None));
+        assert!(buffer.contains(jab) && buffer.contains(kick) && !buffer.contains(hook));
+        assert_eq!(buffer.len(), 2);
+    }
+}
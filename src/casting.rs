@@ -0,0 +1,270 @@
+use bevy::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::SmallVec;
+use crate::{
+    costs::{AbilityItems, CostPayer},
+    levels::ScalableValue,
+    prelude::*,
+};
+
+
+/// How far the caster can drift during a cast before it counts as moving
+const MOVEMENT_TOLERANCE: f32 = 0.01;
+
+/// A wind-up phase that runs before the execution tree starts
+#[derive(Clone)]
+pub struct AbilityCastTime {
+    /// Seconds before the execution tree starts
+    pub duration: ScalableValue,
+    /// Tags added to ActiveTags only while casting, e.g. to block movement
+    pub tags: SmallVec<[TagId; 2]>,
+    /// If false, canceled_by tags are ignored until the cast completes
+    pub interruptible: bool,
+    /// Interrupt the cast if the caster's Transform moves
+    pub canceled_by_movement: bool,
+}
+
+impl AbilityCastTime {
+    pub fn new(seconds: impl Into<ScalableValue>) -> Self {
+        Self {
+            duration: seconds.into(),
+            tags: SmallVec::new(),
+            interruptible: true,
+            canceled_by_movement: false,
+        }
+    }
+
+    pub fn with_tags(mut self, tags: impl IntoIterator<Item = TagId>) -> Self {
+        self.tags.extend(tags);
+        self
+    }
+
+    pub fn uninterruptible(mut self) -> Self {
+        self.interruptible = false;
+        self
+    }
+
+    pub fn canceled_by_movement(mut self) -> Self {
+        self.canceled_by_movement = true;
+        self
+    }
+}
+
+/// Progress of a cast in flight
+#[derive(Clone)]
+pub(crate) struct CastState {
+    pub(crate) timer: Timer,
+    start: Option<Vec3>,
+}
+
+impl CastState {
+    pub(crate) fn new(seconds: f32) -> Self {
+        Self { timer: Timer::from_seconds(seconds, TimerMode::Once), start: None }
+    }
+}
+
+/// Advances casts, interrupting them on movement and starting the execution tree when they complete.
+/// Abilities are committed when their cast completes, so an interrupted cast costs nothing.
+#[allow(clippy::type_complexity)]
+pub(crate) fn update_casts<T: StatTrait>(
+    mut q: Query<(
+        Entity,
+        &mut ActiveAbilities<T>,
+        &mut ActiveTags,
+        &mut AbilityTagCounts,
+        &mut AbilityCooldowns,
+        &mut AbilityCharges,
+        Option<&Transform>,
+    )>,
+    stats: Query<&GameplayStats<T>>,
+    mut items: Query<&mut AbilityItems>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, mut active, mut tags, mut tag_counts, mut cooldowns, mut charges, transform) in q.iter_mut() {
+        if !active.iter().any(|a| a.is_casting()) { continue }
+        let mut items = items.get_mut(entity).ok();
+        let ActiveAbilities { running, reserved, .. } = &mut *active;
+        for ability in running.iter_mut() {
+            let Some(cast) = ability.cast.as_mut() else { continue };
+            let Some(config) = ability.cast_time.as_ref() else { continue };
+            cast.timer.tick(time.delta());
+
+            let position = transform.map(|t| t.translation);
+            if cast.start.is_none() {
+                cast.start = position;
+            }
+            let moved = match (cast.start, position) {
+                (Some(start), Some(position)) => start.distance(position) > MOVEMENT_TOLERANCE,
+                _ => false,
+            };
+            if config.canceled_by_movement && moved {
+                commands.trigger(EndAbility{
+                    entity,
+                    ability: ability.clone(),
+                    outcome: AbilityOutcome::Interrupted,
+                });
+                continue;
+            }
+
+            if !cast.timer.finished() { continue }
+            config.tags.iter().for_each(|t| tag_counts.remove(*t, &mut tags));
+            ability.cast = None;
+            if !ability.is_committed() && ability.costs.policy != CostPolicy::OnCommit {
+                let payer = CostPayer { stats: stats.get(entity).ok(), items: items.as_deref_mut(), reserved };
+                if !ability.try_commit(entity, payer, &mut cooldowns, &mut charges, &mut tags, &mut commands) {
+                    continue;
+                }
+            }
+            ability.spawn_tree(entity, &mut commands);
+            let Some(instance) = ability.instance() else { continue };
+            commands.trigger(CastCompleted{ entity, ability: ability.tags.ability, instance });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_behave::prelude::*;
+    use bevy_gameplay_effects::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    struct Fireball {
+        fireball: TagId,
+        casting: TagId,
+        stunned: TagId,
+    }
+
+    fn fireball(cast_time: AbilityCastTime) -> (Fireball, App) {
+        let mut tags = TagRegistry::new();
+        let fireball = tags.register("Ability.Fireball");
+        let casting = tags.register("Character.State.Casting");
+        let stunned = tags.register("Character.State.Stunned");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(fireball)
+            .with_cast_time(cast_time.with_tags([casting]))
+            .canceled_by([stunned])
+            .with_stat_cost(StatCost::new(TestStats::Mana, 4.))
+            .with_cost_policy(CostPolicy::OnActivate)
+            .with_cooldown(5.)
+            .with_execution_tree(tree! { Behave::Wait(10.) })
+        );
+        let mut app = app(tags, abilities);
+        app.add_plugins(BehavePlugin::new(Update));
+        record(&mut app, |e: &CastCompleted| e.ability);
+        record(&mut app, |e: &CastInterrupted| e.ability);
+        (Fireball { fireball, casting, stunned }, app)
+    }
+
+    fn cooldown(app: &App, entity: Entity, ability: TagId) -> bool {
+        app.world().get::<AbilityCooldowns>(entity).unwrap().is_on_cooldown(ability)
+    }
+
+    #[test]
+    fn casts_commit_when_they_complete() {
+        let (tags, mut app) = fireball(AbilityCastTime::new(1.));
+        let caster = spawn_caster(&mut app, [tags.fireball]);
+
+        try_execute(&mut app, caster, tags.fireball);
+        tick(&mut app, 0.5);
+        assert!(has_tag(&app, caster, tags.casting));
+        assert_eq!(mana(&app, caster), 10.);
+        assert!(!cooldown(&app, caster, tags.fireball));
+
+        tick(&mut app, 0.5);
+        update(&mut app);
+        assert!(!has_tag(&app, caster, tags.casting));
+        assert_eq!(mana(&app, caster), 6.);
+        assert!(cooldown(&app, caster, tags.fireball));
+        assert_eq!(recorded::<CastCompleted, TagId>(&app), vec![tags.fireball]);
+        assert_eq!(running(&app, caster), 1);
+    }
+
+    #[test]
+    fn interrupted_casts_cost_nothing() {
+        let (tags, mut app) = fireball(AbilityCastTime::new(1.));
+        let caster = spawn_caster(&mut app, [tags.fireball]);
+
+        try_execute(&mut app, caster, tags.fireball);
+        tick(&mut app, 0.5);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(tags.stunned);
+        update(&mut app);
+        tick(&mut app, 0.5);
+
+        assert_eq!(recorded::<CastInterrupted, TagId>(&app), vec![tags.fireball]);
+        assert!(recorded::<CastCompleted, TagId>(&app).is_empty());
+        assert!(!has_tag(&app, caster, tags.casting));
+        assert_eq!(mana(&app, caster), 10.);
+        assert!(!cooldown(&app, caster, tags.fireball));
+        assert_eq!(running(&app, caster), 0);
+    }
+
+    #[test]
+    fn casts_that_can_no_longer_pay_fail_when_they_complete() {
+        let (tags, mut app) = fireball(AbilityCastTime::new(1.));
+        record(&mut app, |e: &EndAbility<TestStats>| e.outcome);
+        let caster = spawn_caster(&mut app, [tags.fireball]);
+
+        try_execute(&mut app, caster, tags.fireball);
+        // Something else spends the mana during the cast
+        trigger(&mut app, AddEffect(AddEffectData::<TestStats>::new(
+            caster,
+            GameplayEffect::new(
+                None,
+                TestStats::Mana,
+                EffectMagnitude::Fixed(-8.),
+                EffectCalculation::Additive,
+                EffectDuration::Immediate,
+            ),
+            None,
+        )));
+        tick(&mut app, 1.);
+        update(&mut app);
+
+        assert_eq!(recorded::<EndAbility<TestStats>, AbilityOutcome>(&app), vec![AbilityOutcome::Failed]);
+        assert!(recorded::<CastCompleted, TagId>(&app).is_empty());
+        assert_eq!(running(&app, caster), 0);
+    }
+
+    #[test]
+    fn movement_within_the_tolerance_does_not_interrupt() {
+        let (tags, mut app) = fireball(AbilityCastTime::new(1.).canceled_by_movement());
+        let caster = spawn_caster(&mut app, [tags.fireball]);
+        app.world_mut().entity_mut(caster).insert(Transform::default());
+
+        try_execute(&mut app, caster, tags.fireball);
+        tick(&mut app, 0.25);
+        app.world_mut().get_mut::<Transform>(caster).unwrap().translation.x = 0.005;
+        tick(&mut app, 0.25);
+        assert!(recorded::<CastInterrupted, TagId>(&app).is_empty());
+
+        app.world_mut().get_mut::<Transform>(caster).unwrap().translation.x = 0.5;
+        tick(&mut app, 0.25);
+        update(&mut app);
+        assert_eq!(recorded::<CastInterrupted, TagId>(&app), vec![tags.fireball]);
+        assert_eq!(mana(&app, caster), 10.);
+    }
+
+    #[test]
+    fn overlapping_casts_share_their_cast_tags() {
+        let (tags, mut app) = fireball(AbilityCastTime::new(1.));
+        let heal = app.world_mut().resource_mut::<TagRegistry>().register("Ability.Heal");
+        let definition = AbilityDefinition::new(heal).with_cast_time(AbilityCastTime::new(2.).with_tags([tags.casting]));
+        app.world_mut().resource_mut::<AbilityRegistry<TestStats>>().insert(heal, definition);
+        let caster = spawn_caster(&mut app, [tags.fireball, heal]);
+
+        try_execute(&mut app, caster, tags.fireball);
+        try_execute(&mut app, caster, heal);
+        tick(&mut app, 1.);
+        update(&mut app);
+        assert_eq!(recorded::<CastCompleted, TagId>(&app), vec![tags.fireball]);
+        assert!(has_tag(&app, caster, tags.casting));
+
+        trigger(&mut app, CancelAbility { entity: caster, ability: heal });
+        assert_eq!(recorded::<CastInterrupted, TagId>(&app), vec![heal]);
+        assert!(!has_tag(&app, caster, tags.casting));
+    }
+}
@@ -0,0 +1,163 @@
+use bevy::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use crate::prelude::*;
+
+
+/// Timers for a running channeled ability
+#[derive(Clone)]
+pub(crate) struct ChannelState {
+    duration: Timer,
+    tick: Timer,
+    max_ticks: u32,
+    pub(crate) ticks: u32,
+}
+
+impl ChannelState {
+    pub(crate) fn new(duration: f32, tick_interval: f32) -> Self {
+        Self {
+            duration: Timer::from_seconds(duration, TimerMode::Once),
+            tick: Timer::from_seconds(tick_interval, TimerMode::Repeating),
+            max_ticks: if tick_interval > 0. { (duration / tick_interval) as u32 } else { 0 },
+            ticks: 0,
+        }
+    }
+}
+
+/// Advances channeled abilities, paying their tick costs and triggering AbilityTick.
+/// Channels end as Succeeded when their duration runs out, or Interrupted if a tick can't be paid.
+pub(crate) fn tick_channels<T: StatTrait>(
+    mut q: Query<(Entity, &mut ActiveAbilities<T>)>,
+    stats: Query<&GameplayStats<T>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, mut active) in q.iter_mut() {
+        // Only borrow mutably when something is channeling, so ActiveAbilities isn't marked changed every frame
+        let channeling = active.iter().any(|a| a.channel.is_some() && !a.is_casting() && !a.is_targeting());
+        if !channeling { continue }
+        let stats = stats.get(entity).ok();
+        for ability in active.iter_mut() {
+            // Channels start once their cast completes
+            if ability.is_casting() || ability.is_targeting() { continue }
+            let level = ability.level;
+            let Some(channel) = ability.channel.as_mut() else { continue };
+            channel.duration.tick(time.delta());
+            channel.tick.tick(time.delta());
+            let due = channel.tick.times_finished_this_tick()
+                .min(channel.max_ticks.saturating_sub(channel.ticks));
+            let finished = channel.duration.finished();
+
+            let mut interrupted = false;
+            for paid in 0..due {
+                // Stats haven't been updated by the effects paid earlier this frame yet
+                let affordable = ability.tick_costs.iter().all(|cost| stats.is_some_and(|s| {
+                    s.get(cost.stat).current_value >= cost.amount.at(level) * (paid + 1) as f32
+                }));
+                if !affordable {
+                    interrupted = true;
+                    break;
+                }
+                for cost in ability.tick_costs.iter() {
+                    commands.trigger(AddEffect(AddEffectData::<T>::new(
+                        entity,
+                        GameplayEffect::new(
+                            None,
+                            cost.stat,
+                            EffectMagnitude::Fixed(-cost.amount.at(level)),
+                            EffectCalculation::Additive,
+                            EffectDuration::Immediate,
+                        ),
+                        Some(entity),
+                    )));
+                }
+                let Some(channel) = ability.channel.as_mut() else { break };
+                channel.ticks += 1;
+                let tick = channel.ticks;
+                commands.trigger(AbilityTick{ entity, ability: ability.clone(), tick });
+            }
+
+            if interrupted || finished {
+                let outcome = if interrupted { AbilityOutcome::Interrupted } else { AbilityOutcome::Succeeded };
+                commands.trigger(EndAbility{ entity, ability: ability.clone(), outcome });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    #[derive(Resource, Default)]
+    struct ActiveChanged(bool);
+
+    fn beam(tags: &mut TagRegistry, tick_cost: f32) -> (TagId, AbilitiesPlugin<TestStats>) {
+        let beam = tags.register("Ability.Beam");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(beam)
+            .with_activation_policy(ActivationPolicy::Channeled { duration: 3., tick_interval: 1. })
+            .with_tick_cost(StatCost::new(TestStats::Mana, tick_cost))
+        );
+        (beam, abilities)
+    }
+
+    #[test]
+    fn channels_tick_and_succeed_when_their_duration_ends() {
+        let mut tags = TagRegistry::new();
+        let (beam, abilities) = beam(&mut tags, 1.);
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityTick<TestStats>| e.tick);
+        record(&mut app, |e: &EndAbility<TestStats>| (e.outcome, e.ability.ticks()));
+        let caster = spawn_caster(&mut app, [beam]);
+
+        try_execute(&mut app, caster, beam);
+        // A long frame still only pays for the ticks left in the channel
+        tick(&mut app, 1.);
+        tick(&mut app, 5.);
+        update(&mut app);
+
+        assert_eq!(recorded::<AbilityTick<TestStats>, u32>(&app), vec![1, 2, 3]);
+        assert_eq!(
+            recorded::<EndAbility<TestStats>, (AbilityOutcome, u32)>(&app),
+            vec![(AbilityOutcome::Succeeded, 3)]
+        );
+        assert_eq!(mana(&app, caster), 7.);
+    }
+
+    #[test]
+    fn channels_are_interrupted_when_a_tick_cannot_be_paid() {
+        let mut tags = TagRegistry::new();
+        let (beam, abilities) = beam(&mut tags, 4.);
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &EndAbility<TestStats>| (e.outcome, e.ability.ticks()));
+        let caster = spawn_caster(&mut app, [beam]);
+
+        try_execute(&mut app, caster, beam);
+        tick(&mut app, 3.);
+        update(&mut app);
+
+        assert_eq!(
+            recorded::<EndAbility<TestStats>, (AbilityOutcome, u32)>(&app),
+            vec![(AbilityOutcome::Interrupted, 2)]
+        );
+        assert_eq!(mana(&app, caster), 2.);
+    }
+
+    #[test]
+    fn idle_entities_are_not_marked_changed() {
+        let mut tags = TagRegistry::new();
+        let (beam, abilities) = beam(&mut tags, 1.);
+        let mut app = app(tags, abilities);
+        app.init_resource::<ActiveChanged>();
+        app.add_systems(PostUpdate, |q: Query<Ref<ActiveAbilities<TestStats>>>, mut changed: ResMut<ActiveChanged>| {
+            changed.0 = q.iter().any(|active| active.is_changed());
+        });
+        spawn_caster(&mut app, [beam]);
+
+        update(&mut app);
+        tick(&mut app, 1.);
+        assert!(!app.world().resource::<ActiveChanged>().0);
+    }
+}
@@ -0,0 +1,169 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use crate::{events::AbilityChargeRestored, prelude::AbilityRegistry};
+
+/// Charge settings for an ability.
+/// Each activation uses one charge, and charges come back one at a time.
+#[derive(Clone)]
+pub struct AbilityChargeConfig {
+    pub max: u8,
+    /// Seconds to restore a single charge
+    pub recharge_time: f32,
+}
+
+struct ChargeState {
+    current: u8,
+    max: u8,
+    recharge_time: f32,
+    /// Seconds towards the next charge
+    elapsed: f32,
+}
+
+/// Tracks the charges of an entity's abilities, keyed by ability tag.
+/// Only abilities that are recharging are tracked, the rest have all their charges.
+/// This is added automatically with ActiveAbilities.
+#[derive(Component, Default)]
+pub struct AbilityCharges(HashMap<TagId, ChargeState>);
+
+impl AbilityCharges {
+    pub fn new() -> Self { Self::default() }
+
+    /// Charges left, None if the ability has all of its charges
+    pub fn charges(&self, ability: TagId) -> Option<u8> {
+        self.0.get(&ability).map(|c| c.current)
+    }
+
+    /// None if the ability has all of its charges
+    pub fn max_charges(&self, ability: TagId) -> Option<u8> {
+        self.0.get(&ability).map(|c| c.max)
+    }
+
+    /// Progress towards the next charge from 0 to 1, None if the ability isn't recharging
+    pub fn recharge_fraction(&self, ability: TagId) -> Option<f32> {
+        self.0.get(&ability).map(|c| (c.elapsed / c.recharge_time).min(1.))
+    }
+
+    pub(crate) fn available(&self, ability: TagId, config: &AbilityChargeConfig) -> u8 {
+        self.charges(ability).map_or(config.max, |c| c.min(config.max))
+    }
+
+    /// Uses up a charge and returns how many are left
+    pub(crate) fn consume(&mut self, ability: TagId, config: &AbilityChargeConfig) -> u8 {
+        // Recharging starts from scratch when the first charge is used
+        let state = self.0.entry(ability).or_insert_with(|| ChargeState {
+            current: config.max,
+            max: config.max,
+            recharge_time: config.recharge_time,
+            elapsed: 0.,
+        });
+        state.current = state.current.min(config.max).saturating_sub(1);
+        state.current
+    }
+}
+
+pub(crate) fn recharge_charges<T: StatTrait>(
+    mut q: Query<(Entity, &mut AbilityCharges)>,
+    registry: Res<AbilityRegistry<T>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, mut charges) in q.iter_mut() {
+        if charges.0.is_empty() { continue }
+        charges.0.retain(|ability, state| {
+            // Go by the registered config so changes from hot reloading apply straight away
+            let Some(config) = registry.get(ability).and_then(|d| d.charges.as_ref()) else { return false };
+            state.max = config.max;
+            state.recharge_time = config.recharge_time;
+            state.elapsed += time.delta_secs();
+            // A long frame can restore several charges, the leftover time counts towards the next one
+            while state.current < state.max && state.elapsed >= state.recharge_time {
+                state.elapsed -= state.recharge_time;
+                state.current += 1;
+                commands.trigger(AbilityChargeRestored {
+                    entity,
+                    ability: *ability,
+                    charges: state.current,
+                });
+            }
+            state.current < state.max
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    fn dash_app(max: u8) -> (App, Entity, TagId) {
+        let mut tags = TagRegistry::new();
+        let dash = tags.register("Ability.Dash");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(dash).with_charges(max, 1.));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityChargeRestored| e.charges);
+        let caster = spawn_caster(&mut app, [dash]);
+        (app, caster, dash)
+    }
+
+    fn dash(app: &mut App, caster: Entity, tag: TagId) {
+        try_execute(app, caster, tag);
+        trigger(app, CancelAbility { entity: caster, ability: tag });
+    }
+
+    fn charges(app: &App, caster: Entity, tag: TagId) -> Option<u8> {
+        app.world().get::<AbilityCharges>(caster).unwrap().charges(tag)
+    }
+
+    #[test]
+    fn long_frames_restore_several_charges_and_keep_the_remainder() {
+        let (mut app, caster, tag) = dash_app(3);
+        for _ in 0..3 {
+            dash(&mut app, caster, tag);
+        }
+        assert_eq!(charges(&app, caster, tag), Some(0));
+
+        tick(&mut app, 2.5);
+        assert_eq!(recorded::<AbilityChargeRestored, u8>(&app), vec![1, 2]);
+        assert_eq!(app.world().get::<AbilityCharges>(caster).unwrap().recharge_fraction(tag), Some(0.5));
+
+        tick(&mut app, 0.5);
+        assert_eq!(recorded::<AbilityChargeRestored, u8>(&app), vec![1, 2, 3]);
+        assert_eq!(charges(&app, caster, tag), None);
+    }
+
+    #[test]
+    fn no_charges_left_blocks_activation() {
+        let (mut app, caster, tag) = dash_app(1);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| {
+            matches!(e.reason, ActivationFailureReason::NoCharges)
+        });
+        dash(&mut app, caster, tag);
+        dash(&mut app, caster, tag);
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, bool>(&app), vec![true]);
+    }
+
+    #[test]
+    fn max_charges_follow_the_registered_definition() {
+        let (mut app, caster, tag) = dash_app(2);
+        dash(&mut app, caster, tag);
+        app.world_mut().resource_mut::<AbilityRegistry<TestStats>>().get_mut(&tag).unwrap().charges
+            = Some(AbilityChargeConfig { max: 4, recharge_time: 1. });
+        update(&mut app);
+        assert_eq!(app.world().get::<AbilityCharges>(caster).unwrap().max_charges(tag), Some(4));
+
+        tick(&mut app, 3.);
+        assert_eq!(recorded::<AbilityChargeRestored, u8>(&app), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn zero_max_charges_never_activates() {
+        let (mut app, caster, tag) = dash_app(0);
+        try_execute(&mut app, caster, tag);
+        tick(&mut app, 5.);
+        assert_eq!(running(&app, caster), 0);
+        assert!(recorded::<AbilityChargeRestored, u8>(&app).is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_hierarchical_tags::prelude::*;
+use smallvec::SmallVec;
+use crate::events::ComboReset;
+
+
+/// Links abilities into a chain, e.g. Attack1 -> Attack2 -> Attack3.
+/// Activating the combo's tag with TryActivateAbilityByTag activates whichever step is next.
+#[derive(Clone)]
+pub struct AbilityCombo {
+    /// The tag used to activate the combo, e.g. Ability.Attack
+    pub tag: TagId,
+    /// The ability tags of each step, in order
+    pub steps: SmallVec<[TagId; 4]>,
+    /// Seconds after a step ends during which the next step can be activated
+    pub window: f32,
+    /// Added to ActiveTags while the window is open.  Combos can share one, and it
+    /// stays until the last of their windows closes.
+    pub window_tag: Option<TagId>,
+}
+
+impl AbilityCombo {
+    pub fn new(tag: TagId, steps: impl IntoIterator<Item = TagId>, window: f32) -> Self {
+        Self { tag, steps: steps.into_iter().collect(), window, window_tag: None }
+    }
+
+    pub fn with_window_tag(mut self, tag: TagId) -> Self {
+        self.window_tag = Some(tag);
+        self
+    }
+}
+
+/// Every registered combo, keyed by combo tag and indexed by the abilities of its steps.
+/// Combos can be registered with AbilitiesPlugin::register_combo, or at runtime through this resource.
+#[derive(Resource, Clone, Default)]
+pub struct AbilityCombos {
+    combos: HashMap<TagId, AbilityCombo>,
+    by_step: HashMap<TagId, SmallVec<[TagId; 1]>>,
+}
+
+impl AbilityCombos {
+    /// Add a combo, replacing any combo registered under the same tag
+    pub fn register(&mut self, combo: AbilityCombo) {
+        self.remove(combo.tag);
+        for step in combo.steps.iter() {
+            let combos = self.by_step.entry(*step).or_default();
+            if !combos.contains(&combo.tag) {
+                combos.push(combo.tag);
+            }
+        }
+        self.combos.insert(combo.tag, combo);
+    }
+
+    /// Remove a combo.  Entities part way through it go back to normal activations.
+    pub fn remove(&mut self, tag: TagId) -> Option<AbilityCombo> {
+        let combo = self.combos.remove(&tag)?;
+        for step in combo.steps.iter() {
+            let Some(combos) = self.by_step.get_mut(step) else { continue };
+            combos.retain(|c| *c != tag);
+            if combos.is_empty() {
+                self.by_step.remove(step);
+            }
+        }
+        Some(combo)
+    }
+
+    pub fn get(&self, tag: TagId) -> Option<&AbilityCombo> {
+        self.combos.get(&tag)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AbilityCombo> {
+        self.combos.values()
+    }
+
+    /// The combos that have the ability as one of their steps
+    pub fn with_step(&self, ability: TagId) -> impl Iterator<Item = &AbilityCombo> {
+        self.by_step.get(&ability)
+            .into_iter()
+            .flatten()
+            .filter_map(|tag| self.combos.get(tag))
+    }
+}
+
+#[derive(Default)]
+struct ComboProgress {
+    step: usize,
+    running: bool,
+    window: Option<Timer>,
+    /// The tag added when the window opened
+    window_tag: Option<TagId>,
+}
+
+/// Tracks how far an entity is through each combo.
+/// This is added automatically with ActiveAbilities.
+#[derive(Component, Default)]
+pub struct ComboState(HashMap<TagId, ComboProgress>);
+
+impl ComboState {
+    /// The index of the step the combo is on, 0 if it hasn't started or has reset
+    pub fn step(&self, combo: TagId) -> usize {
+        self.0.get(&combo).map(|p| p.step).unwrap_or(0)
+    }
+
+    /// True while the window to continue the combo is open
+    pub fn window_open(&self, combo: TagId) -> bool {
+        self.0.get(&combo).is_some_and(|p| p.window.is_some())
+    }
+
+    /// The ability an activation of the combo should run.  While a step is
+    /// running this is that step, so pressing again doesn't skip ahead.
+    pub(crate) fn resolve(&self, combo: &AbilityCombo) -> Option<TagId> {
+        let step = match self.0.get(&combo.tag) {
+            Some(p) if p.running || p.window.is_some() => p.step,
+            _ => 0,
+        };
+        combo.steps.get(step).copied()
+    }
+
+    /// A combo step started executing, so its window closes
+    pub(crate) fn started(&mut self, ability: TagId, combos: &AbilityCombos, tags: &mut ActiveTags) {
+        for combo in combos.with_step(ability) {
+            let Some(step) = combo.steps.iter().position(|s| *s == ability) else { continue };
+            let progress = self.0.entry(combo.tag).or_default();
+            let closed = progress.window.take().and(progress.window_tag.take());
+            progress.step = step;
+            progress.running = true;
+            if let Some(tag) = closed.filter(|tag| !self.holds_window_tag(*tag)) {
+                tags.remove(tag);
+            }
+        }
+    }
+
+    /// True if an open window added this tag
+    fn holds_window_tag(&self, tag: TagId) -> bool {
+        self.0.values().any(|p| p.window_tag == Some(tag))
+    }
+
+    /// A combo step ended.  Success opens the window for the next step, anything else resets the combo.
+    pub(crate) fn ended(
+        &mut self,
+        entity: Entity,
+        ability: TagId,
+        succeeded: bool,
+        combos: &AbilityCombos,
+        tags: &mut ActiveTags,
+        commands: &mut Commands,
+    ) {
+        for combo in combos.with_step(ability) {
+            let Some(step) = combo.steps.iter().position(|s| *s == ability) else { continue };
+            let unheld = combo.window_tag.filter(|tag| !self.holds_window_tag(*tag));
+            let Some(progress) = self.0.get_mut(&combo.tag) else { continue };
+            if !progress.running || progress.step != step { continue }
+            progress.running = false;
+            let next = step + 1;
+            if succeeded && next < combo.steps.len() {
+                progress.step = next;
+                progress.window = Some(Timer::from_seconds(combo.window, TimerMode::Once));
+                progress.window_tag = combo.window_tag;
+                unheld.iter().for_each(|t| tags.push(*t));
+                continue;
+            }
+            self.0.remove(&combo.tag);
+            if !succeeded {
+                commands.trigger(ComboReset{ entity, combo: combo.tag });
+            }
+        }
+    }
+}
+
+/// Resets combos whose window ran out before the next step was activated
+pub(crate) fn tick_combo_windows(
+    mut q: Query<(Entity, &mut ComboState, &mut ActiveTags)>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, mut state, mut tags) in q.iter_mut() {
+        if state.0.is_empty() { continue }
+        let mut closed = SmallVec::<[TagId; 2]>::new();
+        state.0.retain(|combo, progress| {
+            let Some(window) = progress.window.as_mut() else { return true };
+            window.tick(time.delta());
+            if !window.finished() { return true }
+            closed.extend(progress.window_tag);
+            commands.trigger(ComboReset{ entity, combo: *combo });
+            false
+        });
+        // A shared tag stays while another combo's window is still open
+        for tag in closed.into_iter().filter(|tag| !state.holds_window_tag(*tag)) {
+            tags.remove(tag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_behave::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    struct Combo {
+        attack: TagId,
+        steps: [TagId; 3],
+        window: TagId,
+    }
+
+    /// A three step combo whose steps succeed straight away, registered at runtime
+    fn combo() -> (Combo, App, Entity) {
+        let mut tags = TagRegistry::new();
+        let attack = tags.register("Ability.Attack");
+        let steps = ["Ability.Attack.1", "Ability.Attack.2", "Ability.Attack.3"].map(|t| tags.register(t));
+        let window = tags.register("Character.State.ComboWindow");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        for step in steps {
+            abilities.register(AbilityDefinition::new(step).with_execution_tree(tree! { Behave::AlwaysSucceed }));
+        }
+        let mut app = app(tags, abilities);
+        app.add_plugins(BehavePlugin::new(Update));
+        app.world_mut().resource_mut::<AbilityCombos>()
+            .register(AbilityCombo::new(attack, steps, 1.).with_window_tag(window));
+        record(&mut app, |e: &ExecuteAbility<TestStats>| e.ability.tags.ability);
+        record(&mut app, |e: &ComboReset| e.combo);
+        let caster = spawn_caster(&mut app, steps);
+        (Combo { attack, steps, window }, app, caster)
+    }
+
+    /// Activate the combo and let the step finish
+    fn press(app: &mut App, caster: Entity, combo: TagId) {
+        trigger(app, TryActivateAbilityByTag::new(caster, combo));
+        update(app);
+        update(app);
+    }
+
+    fn step(app: &App, caster: Entity, combo: TagId) -> usize {
+        app.world().get::<ComboState>(caster).unwrap().step(combo)
+    }
+
+    #[test]
+    fn steps_advance_while_the_window_is_open() {
+        let (combo, mut app, caster) = combo();
+
+        press(&mut app, caster, combo.attack);
+        assert_eq!(step(&app, caster, combo.attack), 1);
+        assert!(has_tag(&app, caster, combo.window));
+        press(&mut app, caster, combo.attack);
+        press(&mut app, caster, combo.attack);
+
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), combo.steps.to_vec());
+        // Finishing the last step starts over without a reset
+        assert_eq!(step(&app, caster, combo.attack), 0);
+        assert!(!has_tag(&app, caster, combo.window));
+        assert!(recorded::<ComboReset, TagId>(&app).is_empty());
+    }
+
+    #[test]
+    fn combos_reset_when_the_window_runs_out() {
+        let (combo, mut app, caster) = combo();
+
+        press(&mut app, caster, combo.attack);
+        tick(&mut app, 0.5);
+        assert!(app.world().get::<ComboState>(caster).unwrap().window_open(combo.attack));
+        tick(&mut app, 0.6);
+
+        assert_eq!(recorded::<ComboReset, TagId>(&app), vec![combo.attack]);
+        assert_eq!(step(&app, caster, combo.attack), 0);
+        assert!(!has_tag(&app, caster, combo.window));
+        press(&mut app, caster, combo.attack);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![combo.steps[0], combo.steps[0]]);
+    }
+
+    #[test]
+    fn cancelled_steps_reset_the_combo() {
+        let (combo, mut app, caster) = combo();
+
+        press(&mut app, caster, combo.attack);
+        trigger(&mut app, TryActivateAbilityByTag::new(caster, combo.attack));
+        trigger(&mut app, CancelAbility{ entity: caster, ability: combo.steps[1] });
+
+        assert_eq!(recorded::<ComboReset, TagId>(&app), vec![combo.attack]);
+        assert_eq!(step(&app, caster, combo.attack), 0);
+    }
+
+    #[test]
+    fn removed_combos_are_dropped_from_the_step_index() {
+        let mut tags = TagRegistry::new();
+        let attack = tags.register("Ability.Attack");
+        let heavy = tags.register("Ability.Heavy");
+        let [slash, stab] = ["Ability.Slash", "Ability.Stab"].map(|t| tags.register(t));
+        let mut combos = AbilityCombos::default();
+        combos.register(AbilityCombo::new(attack, [slash, stab], 1.));
+        combos.register(AbilityCombo::new(heavy, [stab], 1.));
+
+        let with_stab = |combos: &AbilityCombos| combos.with_step(stab).map(|c| c.tag).collect::<Vec<_>>();
+        assert_eq!(with_stab(&combos).len(), 2);
+        assert!(combos.remove(attack).is_some());
+        assert_eq!(with_stab(&combos), vec![heavy]);
+        assert_eq!(combos.with_step(slash).count(), 0);
+
+        // Registering again under the same tag replaces the steps
+        combos.register(AbilityCombo::new(heavy, [slash], 1.));
+        assert_eq!(combos.with_step(stab).count(), 0);
+        assert_eq!(combos.iter().count(), 1);
+    }
+
+    #[test]
+    fn shared_window_tags_stay_until_the_last_window_closes() {
+        let (combo, mut app, caster) = combo();
+        let kick = app.world_mut().resource_mut::<TagRegistry>().register("Ability.Kick");
+        let kicks = ["Ability.Kick.1", "Ability.Kick.2"].map(|t| app.world_mut().resource_mut::<TagRegistry>().register(t));
+        for step in kicks {
+            let definition = AbilityDefinition::new(step).with_execution_tree(tree! { Behave::AlwaysSucceed });
+            app.world_mut().resource_mut::<AbilityRegistry<TestStats>>().insert(step, definition);
+        }
+        app.world_mut().resource_mut::<AbilityCombos>()
+            .register(AbilityCombo::new(kick, kicks, 2.).with_window_tag(combo.window));
+        let granted = GrantedAbilities::<TestStats>::innate(combo.steps.into_iter().chain(kicks));
+        app.world_mut().entity_mut(caster).insert(granted);
+
+        press(&mut app, caster, combo.attack);
+        press(&mut app, caster, kick);
+        // Starting the next attack closes only the attack window
+        trigger(&mut app, TryActivateAbilityByTag::new(caster, combo.attack));
+        assert!(has_tag(&app, caster, combo.window));
+        update(&mut app);
+        update(&mut app);
+        tick(&mut app, 1.1);
+        assert_eq!(recorded::<ComboReset, TagId>(&app), vec![combo.attack]);
+        assert!(has_tag(&app, caster, combo.window));
+        tick(&mut app, 1.);
+        assert_eq!(recorded::<ComboReset, TagId>(&app), vec![combo.attack, kick]);
+        assert!(!has_tag(&app, caster, combo.window));
+    }
+}
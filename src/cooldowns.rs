@@ -0,0 +1,164 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_hierarchical_tags::prelude::*;
+use smallvec::SmallVec;
+use crate::levels::ScalableValue;
+
+/// Cooldown settings for an ability
+#[derive(Clone)]
+pub struct AbilityCooldown {
+    /// Seconds before the ability can be activated again
+    pub duration: ScalableValue,
+    /// Optional tag added to ActiveTags while the cooldown runs.
+    /// Other abilities can be blocked_by it to share a cooldown.  Several cooldowns can use
+    /// the same tag, and it stays until the last of them runs out.
+    pub tag: Option<TagId>,
+}
+
+struct RunningCooldown {
+    timer: Timer,
+    tag: Option<TagId>,
+}
+
+/// Tracks the running cooldowns of an entity, keyed by ability tag.
+/// This is added automatically with ActiveAbilities.
+#[derive(Component, Default)]
+pub struct AbilityCooldowns(HashMap<TagId, RunningCooldown>);
+
+impl AbilityCooldowns {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn is_on_cooldown(&self, ability: TagId) -> bool {
+        self.0.contains_key(&ability)
+    }
+
+    /// Seconds left before the ability is ready, None if it is not cooling down
+    pub fn remaining(&self, ability: TagId) -> Option<f32> {
+        self.0.get(&ability).map(|c| c.timer.remaining_secs())
+    }
+
+    /// Full length of the running cooldown in seconds
+    pub fn total(&self, ability: TagId) -> Option<f32> {
+        self.0.get(&ability).map(|c| c.timer.duration().as_secs_f32())
+    }
+
+    /// Goes from 1 when the cooldown starts to 0 when the ability is ready.
+    /// Handy for drawing radial cooldown sweeps.
+    pub fn fraction_remaining(&self, ability: TagId) -> Option<f32> {
+        self.0.get(&ability).map(|c| c.timer.fraction_remaining())
+    }
+
+    pub(crate) fn start(
+        &mut self,
+        ability: TagId,
+        cooldown: &AbilityCooldown,
+        level: u8,
+        active_tags: &mut ActiveTags,
+    ) {
+        if let Some(tag) = cooldown.tag.filter(|tag| !self.holds_tag(*tag)) {
+            active_tags.push(tag);
+        }
+        let replaced = self.0.insert(ability, RunningCooldown {
+            timer: Timer::from_seconds(cooldown.duration.at(level), TimerMode::Once),
+            tag: cooldown.tag,
+        });
+        if let Some(tag) = replaced.and_then(|c| c.tag).filter(|tag| !self.holds_tag(*tag)) {
+            active_tags.remove(tag);
+        }
+    }
+
+    /// True if a running cooldown adds this tag
+    fn holds_tag(&self, tag: TagId) -> bool {
+        self.0.values().any(|c| c.tag == Some(tag))
+    }
+}
+
+pub(crate) fn tick_cooldowns(
+    mut q: Query<(&mut AbilityCooldowns, &mut ActiveTags)>,
+    time: Res<Time>,
+) {
+    for (mut cooldowns, mut tags) in q.iter_mut() {
+        if cooldowns.0.is_empty() { continue }
+        let mut expired = SmallVec::<[TagId; 2]>::new();
+        cooldowns.0.retain(|_, cooldown| {
+            cooldown.timer.tick(time.delta());
+            if !cooldown.timer.finished() { return true }
+            expired.extend(cooldown.tag);
+            false
+        });
+        // A shared tag stays while another cooldown still uses it
+        for tag in expired.into_iter().filter(|tag| !cooldowns.holds_tag(*tag)) {
+            tags.remove(tag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    #[test]
+    fn cooldowns_block_activation_until_they_run_out() {
+        let mut tags = TagRegistry::new();
+        let blink = tags.register("Ability.Blink");
+        let cooling = tags.register("Cooldown.Blink");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(blink).with_cooldown(2.).with_cooldown_tag(cooling));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| {
+            matches!(e.reason, ActivationFailureReason::OnCooldown { .. })
+        });
+        let caster = spawn_caster(&mut app, [blink]);
+
+        try_execute(&mut app, caster, blink);
+        trigger(&mut app, CancelAbility { entity: caster, ability: blink });
+        assert!(has_tag(&app, caster, cooling));
+
+        tick(&mut app, 1.5);
+        let cooldowns = app.world().get::<AbilityCooldowns>(caster).unwrap();
+        assert_eq!(cooldowns.remaining(blink), Some(0.5));
+        assert_eq!(cooldowns.fraction_remaining(blink), Some(0.25));
+        try_execute(&mut app, caster, blink);
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, bool>(&app), vec![true]);
+
+        tick(&mut app, 0.5);
+        assert!(!app.world().get::<AbilityCooldowns>(caster).unwrap().is_on_cooldown(blink));
+        assert!(!has_tag(&app, caster, cooling));
+        try_execute(&mut app, caster, blink);
+        assert_eq!(running(&app, caster), 1);
+    }
+
+    #[test]
+    fn cooldown_length_scales_with_level() {
+        let mut tags = TagRegistry::new();
+        let blink = tags.register("Ability.Blink");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(blink).with_cooldown(ScalableValue::table([3., 2., 1.])));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [blink]);
+        app.world_mut().get_mut::<GrantedAbilities<TestStats>>(caster).unwrap().set_level(blink, 3);
+
+        try_execute(&mut app, caster, blink);
+        assert_eq!(app.world().get::<AbilityCooldowns>(caster).unwrap().total(blink), Some(1.));
+    }
+
+    #[test]
+    fn shared_cooldown_tags_stay_until_the_last_cooldown_ends() {
+        let mut tags = TagRegistry::new();
+        let blink = tags.register("Ability.Blink");
+        let dash = tags.register("Ability.Dash");
+        let moving = tags.register("Cooldown.Movement");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(blink).with_cooldown(1.).with_cooldown_tag(moving));
+        abilities.register(AbilityDefinition::new(dash).with_cooldown(3.).with_cooldown_tag(moving));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [blink, dash]);
+
+        try_execute(&mut app, caster, dash);
+        try_execute(&mut app, caster, blink);
+        tick(&mut app, 1.5);
+        assert!(has_tag(&app, caster, moving));
+        tick(&mut app, 1.5);
+        assert!(!has_tag(&app, caster, moving));
+    }
+}
@@ -1,6 +1,7 @@
 use bevy::{platform::collections::HashMap, prelude::*};
-use bevy_gameplay_effects::prelude::StatTrait;
+use bevy_gameplay_effects::prelude::*;
 use smallvec::SmallVec;
+use crate::{events::ActivationFailureReason, levels::ScalableValue, prelude::ActiveAbilities};
 
 #[derive(Clone)]
 pub struct ItemCost {
@@ -11,13 +12,169 @@ pub struct ItemCost {
 #[derive(Clone)]
 pub struct StatCost<T: StatTrait> {
     pub stat: T,
-    pub amount: f32,
+    pub amount: ScalableValue,
+}
+
+impl<T: StatTrait> StatCost<T> {
+    pub fn new(stat: T, amount: impl Into<ScalableValue>) -> Self {
+        Self { stat, amount: amount.into() }
+    }
+}
+
+/// Controls if and when the abilities plugin pays an ability's costs
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum CostPolicy {
+    /// Costs are only checked before execution.  Paying them is up to you.
+    #[default]
+    Never,
+    /// Costs are paid at the moment the ability is allowed to execute.
+    /// Abilities with a cast time pay when the cast completes, so an interrupted cast costs nothing.
+    OnActivate,
+    /// Costs are paid when a CommitAbility event is triggered for the running ability
+    OnCommit,
 }
 
 #[derive(Clone, Default)]
 pub struct AbilityCost<T: StatTrait> {
     pub stat_costs: SmallVec<[StatCost<T>; 1]>,
     pub item_costs: SmallVec<[ItemCost; 1]>,
+    pub policy: CostPolicy,
+}
+
+impl<T: StatTrait> AbilityCost<T> {
+    pub(crate) fn can_pay(
+        &self,
+        level: u8,
+        stats: Option<&GameplayStats<T>>,
+        items: Option<&AbilityItems>,
+        reserved: &StatReservations<T>,
+    ) -> Result<(), ActivationFailureReason<T>> {
+        if !self.stat_costs.is_empty() {
+            let Some(stats) = stats else { return Err(ActivationFailureReason::MissingComponent) };
+            for cost in self.stat_costs.iter() {
+                let have = reserved.available(cost.stat, stats.get(cost.stat).current_value);
+                let need = cost.amount.at(level);
+                if have < need {
+                    return Err(ActivationFailureReason::InsufficientStat {
+                        stat: cost.stat, have, need
+                    });
+                }
+            }
+        }
+
+        if !self.item_costs.is_empty() {
+            let Some(items) = items else { return Err(ActivationFailureReason::MissingComponent) };
+            for cost in self.item_costs.iter() {
+                let have = items.get(&cost.item_id).copied().unwrap_or(0);
+                if have < cost.amount as u16 {
+                    return Err(ActivationFailureReason::InsufficientItem {
+                        item_id: cost.item_id, have, need: cost.amount as u16
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deducts the costs from the entity.
+    /// Items are removed immediately, stats are paid with an immediate GameplayEffect
+    /// and reserved until the end of the frame so nothing else can spend them first.
+    /// Always call can_pay first.
+    pub(crate) fn pay(
+        &self,
+        level: u8,
+        entity: Entity,
+        payer: CostPayer<T>,
+        commands: &mut Commands,
+    ) {
+        let CostPayer { stats, items, reserved } = payer;
+        if let Some(items) = items {
+            for cost in self.item_costs.iter() {
+                if let Some(inventory) = items.get_mut(&cost.item_id) {
+                    *inventory = inventory.saturating_sub(cost.amount as u16);
+                }
+            }
+        }
+
+        for cost in self.stat_costs.iter() {
+            let amount = cost.amount.at(level);
+            if let Some(stats) = stats {
+                reserved.reserve(cost.stat, stats.get(cost.stat).current_value, amount);
+            }
+            commands.trigger(AddEffect(AddEffectData::<T>::new(
+                entity,
+                GameplayEffect::new(
+                    None,
+                    cost.stat,
+                    EffectMagnitude::Fixed(-amount),
+                    EffectCalculation::Additive,
+                    EffectDuration::Immediate,
+                ),
+                Some(entity),
+            )));
+        }
+    }
+}
+
+/// What an ability's costs are paid from
+pub(crate) struct CostPayer<'a, T: StatTrait> {
+    pub stats: Option<&'a GameplayStats<T>>,
+    pub items: Option<&'a mut AbilityItems>,
+    pub reserved: &'a mut StatReservations<T>,
+}
+
+struct StatReservation<T: StatTrait> {
+    stat: T,
+    /// The stat's current value when it was first reserved this frame
+    value: f32,
+    amount: f32,
+}
+
+/// Stat costs paid this frame.  The AddEffect that pays them may not have been applied
+/// when the next activation checks its costs, so the amount is held here until the end of the frame.
+pub(crate) struct StatReservations<T: StatTrait>(SmallVec<[StatReservation<T>; 2]>);
+
+impl<T: StatTrait> Default for StatReservations<T> {
+    fn default() -> Self {
+        Self(SmallVec::new())
+    }
+}
+
+impl<T: StatTrait> StatReservations<T> {
+    /// How much of the stat can still be spent
+    pub(crate) fn available(&self, stat: T, current: f32) -> f32 {
+        match self.0.iter().find(|r| r.stat == stat) {
+            // If the effect has already been applied `current` is the lower value
+            Some(reservation) => current.min(reservation.value - reservation.amount),
+            None => current,
+        }
+    }
+
+    pub(crate) fn reserve(&mut self, stat: T, current: f32, amount: f32) {
+        match self.0.iter_mut().find(|r| r.stat == stat) {
+            Some(reservation) => reservation.amount += amount,
+            None => self.0.push(StatReservation { stat, value: current, amount }),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Costs paid this frame have had their effects applied by now
+pub(crate) fn release_stat_reservations<T: StatTrait>(mut q: Query<&mut ActiveAbilities<T>>) {
+    for mut active in q.iter_mut() {
+        // Only deref mutably when there is something to clear to avoid triggering change detection
+        if !active.reserved.is_empty() {
+            active.reserved.clear();
+        }
+    }
 }
 
 #[derive(Component, Deref, DerefMut, Default)]
@@ -25,4 +182,106 @@ pub struct AbilityItems(HashMap<u16, u16>);
 
 impl AbilityItems {
     pub fn new() -> Self { Self::default() }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    #[test]
+    fn activations_in_the_same_frame_cannot_spend_the_same_stats() {
+        let mut tags = TagRegistry::new();
+        let fireball = tags.register("Ability.Fireball");
+        let frostbolt = tags.register("Ability.Frostbolt");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        for tag in [fireball, frostbolt] {
+            abilities.register(AbilityDefinition::new(tag)
+                .with_stat_cost(StatCost::new(TestStats::Mana, 10.))
+                .with_cost_policy(CostPolicy::OnActivate)
+            );
+        }
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &ExecuteAbility<TestStats>| e.ability.tags.ability);
+        let caster = spawn_caster(&mut app, [fireball, frostbolt]);
+
+        let fireball = ability(&app, fireball);
+        let frostbolt = ability(&app, frostbolt);
+        app.world_mut().commands().trigger(TryExecuteAbility { entity: caster, ability: fireball });
+        app.world_mut().commands().trigger(TryExecuteAbility { entity: caster, ability: frostbolt });
+        update(&mut app);
+
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).len(), 1);
+        assert_eq!(mana(&app, caster), 0.);
+    }
+
+    #[test]
+    fn paid_stats_stay_reserved_until_the_end_of_the_frame() {
+        let mut tags = TagRegistry::new();
+        let fireball = tags.register("Ability.Fireball");
+        let frostbolt = tags.register("Ability.Frostbolt");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        for tag in [fireball, frostbolt] {
+            abilities.register(AbilityDefinition::new(tag)
+                .with_stat_cost(StatCost::new(TestStats::Mana, 6.))
+                .with_cost_policy(CostPolicy::OnActivate)
+            );
+        }
+        // Without the effects plugin the cost is never deducted, so only the reservation stops frostbolt
+        let mut app = App::new();
+        app.insert_resource(tags);
+        app.init_resource::<Time>();
+        app.add_plugins(abilities);
+        record(&mut app, |e: &ExecuteAbility<TestStats>| e.ability.tags.ability);
+        let caster = spawn_caster(&mut app, [fireball, frostbolt]);
+
+        try_execute(&mut app, caster, fireball);
+        try_execute(&mut app, caster, frostbolt);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![fireball]);
+
+        update(&mut app);
+        try_execute(&mut app, caster, frostbolt);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![fireball, frostbolt]);
+    }
+
+    #[test]
+    fn never_policy_checks_but_does_not_pay() {
+        let mut tags = TagRegistry::new();
+        let heal = tags.register("Ability.Heal");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(heal).with_stat_cost(StatCost::new(TestStats::Mana, 4.)));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [heal]);
+
+        try_execute(&mut app, caster, heal);
+        assert_eq!(running(&app, caster), 1);
+        assert_eq!(mana(&app, caster), 10.);
+    }
+
+    #[test]
+    fn missing_items_fail_the_check() {
+        let mut tags = TagRegistry::new();
+        let bomb = tags.register("Ability.Bomb");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(bomb)
+            .with_item_cost(ItemCost { item_id: 7, amount: 2 })
+            .with_cost_policy(CostPolicy::OnActivate)
+        );
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| {
+            matches!(e.reason, ActivationFailureReason::InsufficientItem { have: 1, need: 2, .. })
+        });
+        let caster = spawn_caster(&mut app, [bomb]);
+        let mut items = AbilityItems::new();
+        items.insert(7, 1);
+        app.world_mut().entity_mut(caster).insert(items);
+
+        try_execute(&mut app, caster, bomb);
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, bool>(&app), vec![true]);
+
+        app.world_mut().get_mut::<AbilityItems>(caster).unwrap().insert(7, 3);
+        try_execute(&mut app, caster, bomb);
+        assert_eq!(app.world().get::<AbilityItems>(caster).unwrap()[&7], 1);
+    }
+}
@@ -1,6 +1,7 @@
 use bevy::prelude::*;
 use bevy_gameplay_effects::prelude::*;
 use bevy_hierarchical_tags::prelude::*;
+use smallvec::SmallVec;
 use crate::prelude::*;
 
 
@@ -10,21 +11,304 @@ pub struct TryExecuteAbility<T: StatTrait> {
     pub ability: Ability<T>,
 }
 
+/// Activates a granted ability by its tag.  The ability is built from the AbilityRegistry
+/// at its granted level and sent through the same checks as TryExecuteAbility.
+#[derive(Event)]
+pub struct TryActivateAbilityByTag {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub target_data: TargetData,
+}
+
+impl TryActivateAbilityByTag {
+    /// Activate the ability with no target data
+    pub fn new(entity: Entity, ability: TagId) -> Self {
+        Self { entity, ability, target_data: TargetData::None }
+    }
+
+    /// Aim the ability, e.g. at a point picked before activating
+    pub fn with_target_data(mut self, target_data: TargetData) -> Self {
+        self.target_data = target_data;
+        self
+    }
+}
+
+/// Activates the first granted ability under `parent` in the tag hierarchy that passes its checks.
+/// Activating Ability.Attack picks whichever concrete attack is granted and currently allowed.
+/// Candidates are tried by highest input priority, then in the order they were granted.
+#[derive(Event)]
+pub struct TryActivateAbilityByParentTag {
+    pub entity: Entity,
+    pub parent: TagId,
+}
+
 #[derive(Event)]
 pub struct ExecuteAbility<T: StatTrait> {
     pub entity: Entity,
     pub ability: Ability<T>,
 }
 
+/// Sends a gameplay event to an entity.  Any granted ability with a matching
+/// AbilityTrigger::GameplayEvent tries to activate with the data as its trigger_data.
+#[derive(Event)]
+pub struct SendGameplayEvent {
+    pub entity: Entity,
+    pub data: GameplayEventData,
+}
+
+/// How a running ability came to an end
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AbilityOutcome {
+    Succeeded,
+    Failed,
+    /// One of the ability's canceled_by tags appeared
+    Interrupted,
+}
+
+/// Ends the running instance of `ability`.  This is triggered automatically when the
+/// execution tree finishes, but you can also trigger it yourself to end the ability early.
 #[derive(Event)]
 pub struct EndAbility<T: StatTrait> {
     pub entity: Entity,
     pub ability: Ability<T>,
+    pub outcome: AbilityOutcome,
+}
+
+/// Replaces the target data of a running ability, e.g. once the player picks a target
+#[derive(Event)]
+pub struct SetTargetData {
+    pub entity: Entity,
+    pub instance: AbilityInstance,
+    pub data: TargetData,
+}
+
+/// Triggered when an ability with a targeting mode starts waiting for its target
+#[derive(Event)]
+pub struct TargetingStarted {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub instance: AbilityInstance,
+}
+
+/// Confirms the target of an ability that is waiting for one.
+/// What `data` should hold depends on the ability's TargetingMode.
+#[derive(Event)]
+pub struct ConfirmTarget {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub data: TargetData,
+}
+
+/// Cancels an ability that is waiting for its target
+#[derive(Event)]
+pub struct CancelTarget {
+    pub entity: Entity,
+    pub ability: TagId,
+}
+
+/// Triggered when a ConfirmTarget passes its checks.  The ability carries on with
+/// `data` as its target data.
+#[derive(Event)]
+pub struct TargetConfirmed {
+    pub entity: Entity,
+    pub instance: AbilityInstance,
+    pub data: TargetData,
+}
+
+/// Triggered when a ConfirmTarget fails its checks.  The ability keeps waiting.
+#[derive(Event)]
+pub struct TargetRejected {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub reason: TargetRejectedReason,
 }
 
+/// Triggered by ApplyEffectToTargets for each target.  `success` is false if the target
+/// has no GameplayStats or doesn't pass the node's filter.
+#[derive(Event)]
+pub struct ApplyEffectResult {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub target: Entity,
+    pub success: bool,
+}
+
+/// Triggered when an ability with a cast time starts casting
+#[derive(Event)]
+pub struct CastStarted {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub instance: AbilityInstance,
+    /// Length of the cast in seconds
+    pub duration: f32,
+}
+
+/// Triggered when a cast is ended or cancelled before it completes
+#[derive(Event)]
+pub struct CastInterrupted {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub instance: AbilityInstance,
+}
+
+/// Triggered when a cast completes and the execution tree starts
+#[derive(Event)]
+pub struct CastCompleted {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub instance: AbilityInstance,
+}
+
+/// Triggered on every tick of a running channeled ability.  `tick` counts up from 1.
+#[derive(Event)]
+pub struct AbilityTick<T: StatTrait> {
+    pub entity: Entity,
+    pub ability: Ability<T>,
+    pub tick: u32,
+}
+
+/// Cancels the running ability if its tag matches, including child tags.
+/// Cancelling Ability.Grenade will also cancel Ability.Grenade.Throwing.
 #[derive(Event)]
 pub struct CancelAbility {
     pub entity: Entity,
     pub ability: TagId,
 }
 
+/// Cancels one specific running instance of an ability
+#[derive(Event)]
+pub struct CancelAbilityInstance {
+    pub entity: Entity,
+    pub instance: AbilityInstance,
+}
+
+/// Triggered when a running ability was stopped by CancelAbility or
+/// CancelAbilityInstance rather than EndAbility
+#[derive(Event)]
+pub struct AbilityCancelled<T: StatTrait> {
+    pub entity: Entity,
+    pub ability: Ability<T>,
+}
+
+/// Pays the costs of the running ability with this tag if its CostPolicy is OnCommit.
+/// If the costs can no longer be paid the ability is ended.
+#[derive(Event)]
+pub struct CommitAbility {
+    pub entity: Entity,
+    pub ability: TagId,
+}
+
+/// Why a TryExecuteAbility was rejected
+#[derive(Clone)]
+pub enum ActivationFailureReason<T: StatTrait> {
+    /// The entity has not been granted this ability
+    NotGranted,
+    /// These required tags are not present
+    MissingRequiredTags(SmallVec<[TagId; 4]>),
+    /// These blocked_by tags are present
+    BlockedByTags(SmallVec<[TagId; 4]>),
+    /// These canceled_by tags are present, so the ability would be interrupted straight away
+    CanceledByTags(SmallVec<[TagId; 4]>),
+    InsufficientStat { stat: T, have: f32, need: f32 },
+    InsufficientItem { item_id: u16, have: u16, need: u16 },
+    /// The entity is missing a component needed to run or pay for the ability
+    MissingComponent,
+    /// This ability is already running on the entity
+    AlreadyActive,
+    /// The ability's cooldown has this many seconds left
+    OnCooldown { remaining: f32 },
+    /// The ability has used all of its charges
+    NoCharges,
+}
+
+/// Triggered when a combo goes back to its first step because its window ran out,
+/// or a step was cancelled or failed
+#[derive(Event)]
+pub struct ComboReset {
+    pub entity: Entity,
+    pub combo: TagId,
+}
+
+/// Triggered when a TryExecuteAbility fails its checks
+#[derive(Event)]
+pub struct AbilityActivationFailed<T: StatTrait> {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub reason: ActivationFailureReason<T>,
+}
+
+/// Triggered instead of AbilityActivationFailed when an activation blocked by tags is
+/// held in the entity's AbilityInputBuffer.  It is retried when the tags clear.
+#[derive(Event)]
+pub struct AbilityActivationBuffered {
+    pub entity: Entity,
+    pub ability: TagId,
+}
+
+/// Triggered when a buffered activation is dropped because its window ran out
+#[derive(Event)]
+pub struct BufferedActivationExpired {
+    pub entity: Entity,
+    pub ability: TagId,
+}
+
+/// Triggered when an ability with charges commits and uses one up
+#[derive(Event)]
+pub struct AbilityChargeConsumed {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub remaining: u8,
+}
+
+/// Triggered when an ability gets a charge back
+#[derive(Event)]
+pub struct AbilityChargeRestored {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub charges: u8,
+}
+
+/// Grants an ability to an entity at runtime.  GrantedAbilities is added if the entity doesn't have it.
+#[derive(Event)]
+pub struct GrantAbility {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub level: u8,
+    pub source: AbilitySource,
+}
+
+/// Removes one source of an ability.  The ability is only revoked once nothing grants it.
+#[derive(Event)]
+pub struct RevokeAbility {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub source: AbilitySource,
+    /// Cancel the ability if it is running when it is revoked
+    pub cancel_running: bool,
+}
+
+/// Removes everything granted by a source, e.g. when a sword is unequipped
+#[derive(Event)]
+pub struct RevokeAbilitiesFromSource {
+    pub entity: Entity,
+    pub source: AbilitySource,
+    /// Cancel any of the revoked abilities that are running
+    pub cancel_running: bool,
+}
+
+/// Triggered when an entity gains an ability it didn't have before
+#[derive(Event)]
+pub struct AbilityGranted {
+    pub entity: Entity,
+    pub ability: TagId,
+    pub source: AbilitySource,
+}
+
+/// Triggered when an ability is no longer granted by anything
+#[derive(Event)]
+pub struct AbilityRevoked {
+    pub entity: Entity,
+    pub ability: TagId,
+    /// The source whose removal revoked the ability
+    pub source: AbilitySource,
+}
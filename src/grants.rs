@@ -0,0 +1,437 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::{smallvec, SmallVec};
+use crate::{levels::ScalableValue, prelude::*};
+
+
+/// Per-grant tweaks layered on top of the registered definition,
+/// e.g. an item that makes an ability cheaper
+#[derive(Clone)]
+pub struct GrantOverrides<T: StatTrait> {
+    /// Replaces the definition's stat costs
+    pub stat_costs: Option<SmallVec<[StatCost<T>; 1]>>,
+    /// Replaces the definition's cooldown duration
+    pub cooldown: Option<ScalableValue>,
+    /// Added to the definition's magnitudes, replacing any with the same name
+    pub magnitudes: HashMap<String, ScalableValue>,
+}
+
+impl<T: StatTrait> Default for GrantOverrides<T> {
+    fn default() -> Self {
+        Self { stat_costs: None, cooldown: None, magnitudes: HashMap::new() }
+    }
+}
+
+/// Where a granted ability came from, so it can be revoked by the same thing that granted it
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AbilitySource {
+    /// Granted directly, e.g. at spawn
+    Innate,
+    /// Granted by another entity, e.g. an equipped sword
+    Entity(Entity),
+    /// Granted by a buff or anything else identified by a tag
+    Tag(TagId),
+    /// Granted by a talent or skill tree node
+    Talent(u32),
+}
+
+/// A lightweight handle to a definition in the AbilityRegistry
+#[derive(Clone)]
+pub struct GrantedAbility<T: StatTrait> {
+    /// The level the ability runs at, the highest level of all its sources.
+    /// This is recomputed whenever a source is granted or revoked.
+    pub level: u8,
+    pub overrides: Option<Box<GrantOverrides<T>>>,
+    sources: SmallVec<[(AbilitySource, u8); 2]>,
+    /// When the ability was first granted, earlier grants win ties between parent tag candidates
+    order: u32,
+}
+
+impl<T: StatTrait> GrantedAbility<T> {
+    pub fn new(level: u8, source: AbilitySource) -> Self {
+        Self { level, overrides: None, sources: smallvec![(source, level)], order: 0 }
+    }
+
+    /// Everything currently granting this ability.  It stays granted until all are revoked.
+    pub fn sources(&self) -> impl Iterator<Item = AbilitySource> + '_ {
+        self.sources.iter().map(|(source, _)| *source)
+    }
+
+    /// The level this source grants the ability at
+    pub fn source_level(&self, source: AbilitySource) -> Option<u8> {
+        self.sources.iter().find(|(s, _)| *s == source).map(|(_, level)| *level)
+    }
+
+    /// Grants made earlier have a lower order
+    pub fn order(&self) -> u32 {
+        self.order
+    }
+
+    fn add_source(&mut self, source: AbilitySource, level: u8) {
+        match self.sources.iter_mut().find(|(s, _)| *s == source) {
+            Some((_, source_level)) => *source_level = level,
+            None => self.sources.push((source, level)),
+        }
+        self.update_level();
+    }
+
+    /// Returns true if no sources are left
+    fn remove_source(&mut self, source: AbilitySource) -> bool {
+        self.sources.retain(|(s, _)| *s != source);
+        self.update_level();
+        self.sources.is_empty()
+    }
+
+    fn update_level(&mut self) {
+        if let Some(level) = self.sources.iter().map(|(_, level)| *level).max() {
+            self.level = level;
+        }
+    }
+
+    /// Set the level and apply the overrides of this grant to an ability
+    pub(crate) fn apply(&self, ability: &mut Ability<T>) {
+        ability.level = self.level;
+        let Some(overrides) = &self.overrides else { return };
+        if let Some(stat_costs) = &overrides.stat_costs {
+            ability.costs.stat_costs = stat_costs.clone();
+        }
+        if let (Some(duration), Some(cooldown)) = (&overrides.cooldown, &mut ability.cooldown) {
+            cooldown.duration = duration.clone();
+        }
+        for (name, value) in overrides.magnitudes.iter() {
+            ability.magnitudes.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// This component stores the abilities the entity is allowed to execute.
+/// Grants only store a level and optional overrides, the definitions stay in the AbilityRegistry.
+#[derive(Component, Clone)]
+pub struct GrantedAbilities<T: StatTrait> {
+    abilities: HashMap<TagId, GrantedAbility<T>>,
+    next_order: u32,
+}
+
+impl<T: StatTrait> Default for GrantedAbilities<T> {
+    fn default() -> Self {
+        Self { abilities: HashMap::new(), next_order: 0 }
+    }
+}
+
+impl<T: StatTrait> GrantedAbilities<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grant innate abilities at level 1
+    pub fn innate(tags: impl IntoIterator<Item = TagId>) -> Self {
+        Self::innate_with_levels(tags.into_iter().map(|t| (t, 1)))
+    }
+
+    /// Grant innate abilities at the given levels
+    pub fn innate_with_levels(tags: impl IntoIterator<Item = (TagId, u8)>) -> Self {
+        let mut granted = Self::new();
+        for (tag, level) in tags {
+            granted.grant(tag, level);
+        }
+        granted
+    }
+
+    /// Grant innate abilities at level 1, skipping any that aren't registered
+    #[deprecated(note = "grants no longer copy definitions, use GrantedAbilities::innate")]
+    pub fn from_tags(tags: impl IntoIterator<Item = TagId>, registry: &AbilityRegistry<T>) -> Self {
+        Self::innate(tags.into_iter().filter(|t| registry.contains_key(t)))
+    }
+
+    /// Grant innate abilities at the given levels, skipping any that aren't registered
+    #[deprecated(note = "grants no longer copy definitions, use GrantedAbilities::innate_with_levels")]
+    pub fn from_tags_with_levels(
+        tags: impl IntoIterator<Item = (TagId, u8)>,
+        registry: &AbilityRegistry<T>,
+    ) -> Self {
+        Self::innate_with_levels(tags.into_iter().filter(|(t, _)| registry.contains_key(t)))
+    }
+
+    /// Grant an innate ability
+    pub fn grant(&mut self, tag: TagId, level: u8) -> &mut GrantedAbility<T> {
+        self.grant_from(tag, level, AbilitySource::Innate)
+    }
+
+    /// Grant an ability from a source.  If it is already granted the source is added,
+    /// and the ability runs at the highest level of all its sources.
+    /// Granting again from the same source changes that source's level.
+    pub fn grant_from(&mut self, tag: TagId, level: u8, source: AbilitySource) -> &mut GrantedAbility<T> {
+        let order = self.next_order;
+        let granted = self.abilities.entry(tag).or_insert_with(|| GrantedAbility {
+            level,
+            overrides: None,
+            sources: SmallVec::new(),
+            order,
+        });
+        if granted.order == order {
+            self.next_order += 1;
+        }
+        granted.add_source(source, level);
+        granted
+    }
+
+    /// Remove the ability no matter what granted it
+    pub fn revoke(&mut self, tag: TagId) -> Option<GrantedAbility<T>> {
+        self.abilities.remove(&tag)
+    }
+
+    /// Remove one source of the ability.  Returns true if nothing grants it anymore.
+    pub fn revoke_from(&mut self, tag: TagId, source: AbilitySource) -> bool {
+        let Some(granted) = self.abilities.get_mut(&tag) else { return false };
+        if !granted.remove_source(source) { return false }
+        self.abilities.remove(&tag);
+        true
+    }
+
+    /// Remove a source from every ability.  Returns the abilities that are no longer granted.
+    pub fn revoke_source(&mut self, source: AbilitySource) -> SmallVec<[TagId; 4]> {
+        let mut revoked = SmallVec::new();
+        self.abilities.retain(|tag, granted| {
+            if granted.remove_source(source) {
+                revoked.push(*tag);
+                return false;
+            }
+            true
+        });
+        revoked
+    }
+
+    /// Move a grant to a new tag, e.g. when an ability file changes its tag.
+    /// If the new tag is already granted that grant is kept.
+    pub(crate) fn rename(&mut self, from: TagId, to: TagId) {
+        let Some(granted) = self.abilities.remove(&from) else { return };
+        self.abilities.entry(to).or_insert(granted);
+    }
+
+    pub fn contains(&self, tag: TagId) -> bool {
+        self.abilities.contains_key(&tag)
+    }
+
+    pub fn get(&self, tag: TagId) -> Option<&GrantedAbility<T>> {
+        self.abilities.get(&tag)
+    }
+
+    pub fn get_mut(&mut self, tag: TagId) -> Option<&mut GrantedAbility<T>> {
+        self.abilities.get_mut(&tag)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (TagId, &GrantedAbility<T>)> {
+        self.abilities.iter().map(|(tag, granted)| (*tag, granted))
+    }
+
+    pub fn len(&self) -> usize {
+        self.abilities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.abilities.is_empty()
+    }
+
+    /// The registered definition of a granted ability
+    pub fn definition<'a>(&self, tag: TagId, registry: &'a AbilityRegistry<T>) -> Option<&'a AbilityDefinition<T>> {
+        if !self.contains(tag) { return None }
+        registry.get(&tag)
+    }
+
+    /// A copy of the registered definition of a granted ability
+    #[deprecated(note = "grants no longer copy definitions, use GrantedAbilities::definition")]
+    pub fn get_from_tag(&self, tag: TagId, registry: &AbilityRegistry<T>) -> Option<AbilityDefinition<T>> {
+        self.definition(tag, registry).cloned()
+    }
+
+    /// Build a granted ability at its granted level with overrides applied, ready for TryExecuteAbility
+    pub fn ability(&self, tag: TagId, registry: &AbilityRegistry<T>) -> Option<Ability<T>> {
+        let granted = self.get(tag)?;
+        let mut ability = Ability::from(registry.get(&tag)?);
+        granted.apply(&mut ability);
+        Some(ability)
+    }
+
+    /// The level the ability was granted at
+    pub fn level(&self, tag: TagId) -> Option<u8> {
+        self.get(tag).map(|g| g.level)
+    }
+
+    /// Change the level of a granted ability for all of its sources.  Returns false if it isn't granted.
+    /// Abilities that are already running keep their old level.
+    pub fn set_level(&mut self, tag: TagId, level: u8) -> bool {
+        let Some(granted) = self.get_mut(tag) else { return false };
+        granted.sources.iter_mut().for_each(|(_, source_level)| *source_level = level);
+        granted.level = level;
+        true
+    }
+}
+
+pub(crate) fn grant_ability<T: StatTrait>(
+    trigger: Trigger<GrantAbility>,
+    mut granted: Query<&mut GrantedAbilities<T>>,
+    entities: Query<()>,
+    mut commands: Commands,
+) {
+    let GrantAbility{ entity, ability, level, source } = trigger.event();
+    let Ok(mut granted) = granted.get_mut(*entity) else {
+        if entities.contains(*entity) {
+            // Add an empty GrantedAbilities and grant again once it is there.  Inserting a filled one
+            // would replace it if another grant to the same entity is waiting in the same frame.
+            commands.entity(*entity).entry::<GrantedAbilities<T>>().or_default();
+            commands.trigger(GrantAbility{ entity: *entity, ability: *ability, level: *level, source: *source });
+        }
+        return;
+    };
+    let newly_granted = !granted.contains(*ability);
+    granted.grant_from(*ability, *level, *source);
+    if newly_granted {
+        commands.trigger(AbilityGranted{ entity: *entity, ability: *ability, source: *source });
+    }
+}
+
+pub(crate) fn revoke_ability<T: StatTrait>(
+    trigger: Trigger<RevokeAbility>,
+    mut granted: Query<&mut GrantedAbilities<T>>,
+    active: Query<&ActiveAbilities<T>>,
+    mut commands: Commands,
+) {
+    let RevokeAbility{ entity, ability, source, cancel_running } = trigger.event();
+    let Ok(mut granted) = granted.get_mut(*entity) else { return };
+    if granted.revoke_from(*ability, *source) {
+        on_revoked(*entity, *ability, *source, *cancel_running, &active, &mut commands);
+    }
+}
+
+pub(crate) fn revoke_abilities_from_source<T: StatTrait>(
+    trigger: Trigger<RevokeAbilitiesFromSource>,
+    mut granted: Query<&mut GrantedAbilities<T>>,
+    active: Query<&ActiveAbilities<T>>,
+    mut commands: Commands,
+) {
+    let RevokeAbilitiesFromSource{ entity, source, cancel_running } = trigger.event();
+    let Ok(mut granted) = granted.get_mut(*entity) else { return };
+    for ability in granted.revoke_source(*source) {
+        on_revoked(*entity, ability, *source, *cancel_running, &active, &mut commands);
+    }
+}
+
+fn on_revoked<T: StatTrait>(
+    entity: Entity,
+    ability: TagId,
+    source: AbilitySource,
+    cancel_running: bool,
+    active: &Query<&ActiveAbilities<T>>,
+    commands: &mut Commands,
+) {
+    if let Some(active) = active.get(entity).ok().filter(|_| cancel_running) {
+        // Only this exact ability, not its children, which may still be granted
+        active.iter()
+            .filter(|a| a.tags.ability == ability)
+            .filter_map(|a| a.instance())
+            .for_each(|instance| commands.trigger(CancelAbilityInstance{ entity, instance }));
+    }
+    commands.trigger(AbilityRevoked{ entity, ability, source });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_constructors_skip_unregistered_abilities() {
+        let mut tags = TagRegistry::new();
+        let stun = tags.register("Ability.Stun");
+        let dash = tags.register("Ability.Dash");
+        let mut registry = AbilityRegistry::<TestStats>::new();
+        registry.insert(stun, AbilityDefinition::new(stun));
+
+        let granted = GrantedAbilities::from_tags([stun, dash], &registry);
+        assert!(granted.contains(stun) && !granted.contains(dash));
+        let granted = GrantedAbilities::from_tags_with_levels([(stun, 3), (dash, 2)], &registry);
+        assert_eq!(granted.level(stun), Some(3));
+        assert_eq!(granted.get_from_tag(stun, &registry).map(|d| d.tags.ability), Some(stun));
+    }
+
+    #[test]
+    fn level_is_the_highest_of_the_remaining_sources() {
+        let mut tags = TagRegistry::new();
+        let slash = tags.register("Ability.Slash");
+        let sword = AbilitySource::Talent(1);
+        let mut granted = GrantedAbilities::<TestStats>::innate_with_levels([(slash, 1)]);
+
+        granted.grant_from(slash, 4, sword);
+        assert_eq!(granted.level(slash), Some(4));
+        assert!(!granted.revoke_from(slash, sword));
+        assert_eq!(granted.level(slash), Some(1));
+        assert!(granted.revoke_from(slash, AbilitySource::Innate));
+        assert!(!granted.contains(slash));
+    }
+
+    fn grant(app: &mut App, entity: Entity, ability: TagId, source: AbilitySource) {
+        app.world_mut().commands().trigger(GrantAbility { entity, ability, level: 1, source });
+    }
+
+    #[test]
+    fn grants_in_the_same_frame_are_all_kept() {
+        let mut tags = TagRegistry::new();
+        let slash = tags.register("Ability.Slash");
+        let parry = tags.register("Ability.Parry");
+        let mut app = app(tags, AbilitiesPlugin::<TestStats>::new());
+        record(&mut app, |e: &AbilityGranted| e.ability);
+        let entity = app.world_mut().spawn_empty().id();
+
+        grant(&mut app, entity, slash, AbilitySource::Innate);
+        grant(&mut app, entity, parry, AbilitySource::Innate);
+        update(&mut app);
+
+        let granted = app.world().get::<GrantedAbilities<TestStats>>(entity).unwrap();
+        assert!(granted.contains(slash) && granted.contains(parry));
+        assert_eq!(recorded::<AbilityGranted, TagId>(&app).len(), 2);
+    }
+
+    #[test]
+    fn overlapping_sources_keep_the_ability_until_the_last_is_revoked() {
+        let mut tags = TagRegistry::new();
+        let slash = tags.register("Ability.Slash");
+        let mut app = app(tags, AbilitiesPlugin::<TestStats>::new());
+        record(&mut app, |e: &AbilityRevoked| e.source);
+        let caster = spawn_caster(&mut app, []);
+        let sword = AbilitySource::Entity(app.world_mut().spawn_empty().id());
+        let buff = AbilitySource::Talent(7);
+
+        grant(&mut app, caster, slash, sword);
+        grant(&mut app, caster, slash, buff);
+        update(&mut app);
+        trigger(&mut app, RevokeAbilitiesFromSource { entity: caster, source: sword, cancel_running: false });
+        assert!(app.world().get::<GrantedAbilities<TestStats>>(caster).unwrap().contains(slash));
+        assert!(recorded::<AbilityRevoked, AbilitySource>(&app).is_empty());
+
+        trigger(&mut app, RevokeAbility { entity: caster, ability: slash, source: buff, cancel_running: false });
+        assert!(!app.world().get::<GrantedAbilities<TestStats>>(caster).unwrap().contains(slash));
+        assert!(recorded::<AbilityRevoked, AbilitySource>(&app) == vec![buff]);
+    }
+
+    #[test]
+    fn revoking_can_cancel_the_running_ability() {
+        let mut tags = TagRegistry::new();
+        let slash = tags.register("Ability.Slash");
+        let parry = tags.register("Ability.Parry");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(slash));
+        abilities.register(AbilityDefinition::new(parry));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [slash, parry]);
+        try_execute(&mut app, caster, slash);
+        try_execute(&mut app, caster, parry);
+
+        trigger(&mut app, RevokeAbility { entity: caster, ability: slash, source: AbilitySource::Innate, cancel_running: true });
+        trigger(&mut app, RevokeAbility { entity: caster, ability: parry, source: AbilitySource::Innate, cancel_running: false });
+        let active = app.world().get::<ActiveAbilities<TestStats>>(caster).unwrap();
+        assert!(!active.is_active(slash) && active.is_active(parry));
+    }
+}
@@ -0,0 +1,82 @@
+use smallvec::SmallVec;
+
+/// A number that can change with the ability level.
+/// Levels start at 1.
+#[derive(Clone)]
+pub enum ScalableValue {
+    /// The same at every level
+    Fixed(f32),
+    /// One entry per level starting at level 1.  Levels past the end use the last entry.
+    Table(SmallVec<[f32; 8]>),
+    /// base + per_level * (level - 1)
+    Linear { base: f32, per_level: f32 },
+    /// Any function of the level.  Level 0 is passed in as 1.
+    Curve(fn(u8) -> f32),
+}
+
+impl ScalableValue {
+    pub fn table(values: impl IntoIterator<Item = f32>) -> Self {
+        Self::Table(values.into_iter().collect())
+    }
+
+    pub fn at(&self, level: u8) -> f32 {
+        let index = level.max(1) - 1;
+        match self {
+            Self::Fixed(value) => *value,
+            Self::Table(values) => values
+                .get(index as usize)
+                .or(values.last())
+                .copied()
+                .unwrap_or(0.),
+            Self::Linear { base, per_level } => base + per_level * index as f32,
+            Self::Curve(f) => f(level.max(1)),
+        }
+    }
+}
+
+impl Default for ScalableValue {
+    fn default() -> Self {
+        Self::Fixed(0.)
+    }
+}
+
+impl From<f32> for ScalableValue {
+    fn from(value: f32) -> Self {
+        Self::Fixed(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_clamps_to_last_level() {
+        let value = ScalableValue::table([10., 20., 30.]);
+        assert_eq!(value.at(0), 10.);
+        assert_eq!(value.at(1), 10.);
+        assert_eq!(value.at(3), 30.);
+        assert_eq!(value.at(9), 30.);
+    }
+
+    #[test]
+    fn linear_starts_at_base() {
+        let value = ScalableValue::Linear { base: 25., per_level: 5. };
+        assert_eq!(value.at(1), 25.);
+        assert_eq!(value.at(3), 35.);
+    }
+
+    #[test]
+    fn curve_never_sees_level_zero() {
+        let value = ScalableValue::Curve(|level| level as f32 * 2.);
+        assert_eq!(value.at(0), 2.);
+        assert_eq!(value.at(4), 8.);
+    }
+
+    #[test]
+    fn numbers_convert_to_fixed_values() {
+        let value: ScalableValue = 12.5.into();
+        assert_eq!(value.at(0), 12.5);
+        assert_eq!(value.at(200), 12.5);
+    }
+}
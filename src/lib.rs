@@ -6,44 +6,142 @@ use crate::prelude::*;
 
 mod ability_definition;
 mod ability;
+mod activation;
+mod grants;
+mod passive;
+mod toggle;
+mod channel;
+mod casting;
+mod buffer;
+mod combo;
+mod target_data;
+mod targeting;
+mod area;
+mod nodes;
 mod costs;
+mod cooldowns;
+mod charges;
+mod levels;
 mod tags;
+mod triggers;
 mod events;
+#[cfg(any(feature = "ron", feature = "json"))]
+mod asset;
+#[cfg(test)]
+mod testing;
 
 pub mod prelude {
     pub use crate::{
         AbilitiesPlugin, AbilityRegistry,
         ability_definition::AbilityDefinition,
-        ability::{Ability, GrantedAbilities, CurrentAbility},
-        tags::{AbilityTags},
-        costs::{ItemCost, StatCost, AbilityItems},
+        ability::{Ability, AbilityInstance, ActiveAbilities},
+        activation::{AbilityCommandsExt, ActivationPolicy},
+        grants::{AbilitySource, GrantedAbilities, GrantedAbility, GrantOverrides},
+        tags::{AbilityTagCounts, AbilityTags},
+        costs::{ItemCost, StatCost, AbilityItems, CostPolicy},
+        casting::AbilityCastTime,
+        buffer::AbilityInputBuffer,
+        passive::PassiveState,
+        combo::{AbilityCombo, AbilityCombos, ComboState},
+        target_data::TargetData,
+        targeting::{TargetFilter, TargetingMode, TargetRejectedReason, TargetValidator},
+        area::{AbilityAreaQuery, AbilitySpatialHash, AreaShape},
+        nodes::{AddTags, ApplyEffectToTargets, CommitCosts, EndCurrentAbility, RemoveTags, WaitForTag, WaitSeconds},
+        cooldowns::{AbilityCooldown, AbilityCooldowns},
+        charges::{AbilityChargeConfig, AbilityCharges},
+        levels::ScalableValue,
+        triggers::{AbilityTrigger, AbilityTriggerState, GameplayEventData},
         events::*,
     };
+    #[allow(deprecated)]
+    pub use crate::ability::CurrentAbility;
+    #[cfg(any(feature = "ron", feature = "json"))]
+    pub use crate::asset::{AbilityAsset, AbilityAssetError, AbilityAssetPlugin, AbilityTrees};
 }
 
 pub struct AbilitiesPlugin<T: StatTrait> {
     abilities: AbilityRegistry<T>,
+    combos: AbilityCombos,
+    add_target_validator: fn(&mut App),
+}
+
+impl<T: StatTrait> Default for AbilitiesPlugin<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: StatTrait> AbilitiesPlugin<T> {
     pub fn new() -> Self {
-        Self { abilities: AbilityRegistry::<T>::new() }
+        Self {
+            abilities: AbilityRegistry::<T>::new(),
+            combos: AbilityCombos::default(),
+            add_target_validator: |app| { app.add_observer(targeting::confirm_target::<T, ()>); },
+        }
     }
 
     pub fn register(&mut self, ability: AbilityDefinition<T>) {
         self.abilities.insert(ability.tags.ability, ability);
     }
+
+    /// Register a combo.  Its steps must be registered as abilities and granted separately.
+    pub fn register_combo(&mut self, combo: AbilityCombo) {
+        self.combos.register(combo);
+    }
+
+    /// Use a TargetValidator, e.g. a line of sight raycast, when targets are confirmed
+    pub fn set_target_validator<V: TargetValidator>(&mut self) {
+        self.add_target_validator = |app| { app.add_observer(targeting::confirm_target::<T, V>); };
+    }
 }
 
 impl<T: StatTrait> Plugin for AbilitiesPlugin<T> {
     fn build(&self, app: &mut App) {
         app.insert_resource(self.abilities.clone());
+        app.insert_resource(self.combos.clone());
         app.add_observer(ability::check_ability_constraints::<T>);
         app.add_observer(ability::execute_ability::<T>);
         app.add_observer(ability::end_ability::<T>);
+        app.add_observer(ability::commit_ability::<T>);
+        app.add_observer(ability::cancel_ability::<T>);
+        app.add_observer(ability::cancel_ability_instance::<T>);
+        app.add_observer(activation::try_activate_by_tag::<T>);
+        app.add_observer(activation::try_activate_by_parent_tag::<T>);
+        app.add_observer(triggers::on_gameplay_event::<T>);
+        app.add_observer(target_data::set_target_data::<T>);
+        app.add_observer(targeting::target_confirmed::<T>);
+        app.add_observer(targeting::cancel_target::<T>);
+        app.add_observer(nodes::apply_effect_to_targets::<T>);
+        app.add_observer(nodes::commit_costs::<T>);
+        app.add_observer(nodes::add_tags::<T>);
+        app.add_observer(nodes::remove_tags::<T>);
+        app.add_observer(nodes::end_current_ability::<T>);
+        (self.add_target_validator)(app);
+        app.add_observer(grants::grant_ability::<T>);
+        app.add_observer(grants::revoke_ability::<T>);
+        app.add_observer(grants::revoke_abilities_from_source::<T>);
         app.add_systems(Update, (
             ability::check_ability_canceled::<T>,
+            ability::check_ability_tree_finished::<T>,
+            triggers::check_ability_triggers::<T>,
+            passive::update_passive_abilities::<T>,
+            toggle::drain_stats::<T>,
+            channel::tick_channels::<T>,
+            casting::update_casts::<T>,
+            buffer::retry_buffered_activations::<T>,
+            combo::tick_combo_windows,
+            cooldowns::tick_cooldowns,
+            charges::recharge_charges::<T>,
+            nodes::tick_wait_seconds::<T>,
+            nodes::check_wait_for_tag,
         ));
+        app.add_systems(PostUpdate, area::update_spatial_hash
+            .after(bevy::transform::TransformSystem::TransformPropagate)
+            .run_if(resource_exists::<AbilitySpatialHash>)
+        );
+        app.add_systems(Last, costs::release_stat_reservations::<T>);
+        #[allow(deprecated)]
+        app.add_systems(Last, ability::sync_current_ability::<T>);
     }
 }
 
@@ -56,10 +154,8 @@ impl<T: StatTrait> AbilityRegistry<T> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn test_ability() {
-
+impl<T: StatTrait> Default for AbilityRegistry<T> {
+    fn default() -> Self {
+        Self::new()
     }
-}
\ No newline at end of file
+}
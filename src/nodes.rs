@@ -0,0 +1,598 @@
+use bevy::prelude::*;
+use bevy_behave::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::SmallVec;
+use crate::{ability::AbilityEnding, area::{AbilityAreaQuery, AreaShape}, prelude::*};
+
+
+/// A behave trigger that applies a GameplayEffect to the running ability's targets,
+/// with the caster as the source.  Use it with `Behave::trigger(ApplyEffectToTargets::new(effect))`.
+/// Entity targets get the effect directly.  Point targets need a radius, and every entity
+/// with ActiveTags within it is hit, except the caster unless include_caster is set, the same
+/// as the Radius and Cone targeting modes.  Direction targets, and points without a radius,
+/// log a warning and fail the node.  Each target reports an ApplyEffectResult.
+#[derive(Clone)]
+pub struct ApplyEffectToTargets<T: StatTrait> {
+    pub effect: GameplayEffect<T>,
+    pub filter: TargetFilter,
+    /// Radius around a TargetData::Point to look for targets in
+    pub radius: Option<f32>,
+    /// Only succeed if every target got the effect, rather than at least one
+    pub require_all: bool,
+    /// Let a radius around a point hit the caster too
+    pub include_caster: bool,
+}
+
+impl<T: StatTrait> ApplyEffectToTargets<T> {
+    pub fn new(effect: GameplayEffect<T>) -> Self {
+        Self { effect, filter: TargetFilter::new(), radius: None, require_all: false, include_caster: false }
+    }
+
+    pub fn with_filter(mut self, filter: TargetFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = Some(radius);
+        self
+    }
+
+    pub fn require_all(mut self) -> Self {
+        self.require_all = true;
+        self
+    }
+
+    pub fn include_caster(mut self) -> Self {
+        self.include_caster = true;
+        self
+    }
+}
+
+/// Applies the effect to every target that has GameplayStats and passes the filter.
+/// The node fails if there were no targets, or if a target was skipped and require_all is set.
+pub(crate) fn apply_effect_to_targets<T: StatTrait>(
+    trigger: Trigger<BehaveTrigger<ApplyEffectToTargets<T>>>,
+    casters: Query<&ActiveAbilities<T>>,
+    targets: Query<Option<&ActiveTags>, With<GameplayStats<T>>>,
+    area: AbilityAreaQuery,
+    tag_registry: Res<TagRegistry>,
+    mut commands: Commands,
+) {
+    let ctx = trigger.event().ctx();
+    let node = trigger.event().inner();
+    let caster = ctx.target_entity();
+    let ability = casters.get(caster).ok().and_then(|active| active.get_by_ctx(ctx));
+    let Some(ability) = ability else {
+        commands.trigger(ctx.failure());
+        return
+    };
+
+    // Area queries already apply the filter
+    let (hit, filtered): (SmallVec<[Entity; 8]>, bool) = match (&ability.target_data, node.radius) {
+        (TargetData::Point(point), Some(radius)) => {
+            let mut hit = area.query(&AreaShape::Sphere { center: *point, radius }, &node.filter);
+            hit.retain(|e| node.include_caster || *e != caster);
+            (hit, true)
+        }
+        (TargetData::Point(_), None) => {
+            warn!("ApplyEffectToTargets needs with_radius to hit the point target of {:?}", ability.tags.ability);
+            (SmallVec::new(), true)
+        }
+        (TargetData::Direction(_), _) => {
+            warn!("ApplyEffectToTargets can't hit the direction target of {:?}", ability.tags.ability);
+            (SmallVec::new(), true)
+        }
+        (data, _) => (SmallVec::from_slice(data.entities()), false),
+    };
+
+    let mut applied = 0;
+    for target in hit.iter().copied() {
+        let success = targets.get(target)
+            .is_ok_and(|tags| filtered || node.filter.matches(tags, &tag_registry));
+        if success {
+            applied += 1;
+            commands.trigger(AddEffect(AddEffectData::<T>::new(target, node.effect.clone(), Some(caster))));
+        }
+        commands.trigger(ApplyEffectResult{ entity: caster, ability: ability.tags.ability, target, success });
+    }
+
+    let success = applied > 0 && (!node.require_all || applied == hit.len());
+    commands.trigger(if success { ctx.success() } else { ctx.failure() });
+}
+
+/// Waits before succeeding.  Unlike Behave::Wait the time can scale with the ability level.
+/// Use it with `Behave::spawn(WaitSeconds::new(2.))`.
+#[derive(Component, Clone)]
+pub struct WaitSeconds {
+    pub seconds: ScalableValue,
+    elapsed: f32,
+    reported: bool,
+}
+
+impl WaitSeconds {
+    pub fn new(seconds: impl Into<ScalableValue>) -> Self {
+        Self { seconds: seconds.into(), elapsed: 0., reported: false }
+    }
+}
+
+/// Pays the costs, uses a charge and starts the cooldown of an ability with CostPolicy::OnCommit.
+/// Fails if the costs can't be paid, which ends the ability.  Succeeds straight away if there
+/// is nothing left to commit.  Use it with `Behave::trigger(CommitCosts)`.
+#[derive(Clone)]
+pub struct CommitCosts;
+
+/// Adds tags to the caster until a RemoveTags node removes them or the ability ends,
+/// e.g. to block movement for part of the tree.  Use it with `Behave::trigger(AddTags::new([tag]))`.
+#[derive(Clone)]
+pub struct AddTags(pub SmallVec<[TagId; 2]>);
+
+impl AddTags {
+    pub fn new(tags: impl IntoIterator<Item = TagId>) -> Self {
+        Self(tags.into_iter().collect())
+    }
+}
+
+/// Removes tags from the caster.  Tags this ability added with AddTags stay while another
+/// ability also holds them.  Use it with `Behave::trigger(RemoveTags::new([tag]))`.
+#[derive(Clone)]
+pub struct RemoveTags(pub SmallVec<[TagId; 2]>);
+
+impl RemoveTags {
+    pub fn new(tags: impl IntoIterator<Item = TagId>) -> Self {
+        Self(tags.into_iter().collect())
+    }
+}
+
+/// Waits until the caster has a tag (or one of its children), or until it's gone.
+/// Fails if the timeout runs out first.  Use it with `Behave::spawn(WaitForTag::added(tag))`.
+#[derive(Component, Clone)]
+pub struct WaitForTag {
+    pub tag: TagId,
+    /// Wait for the tag to be present rather than absent
+    pub present: bool,
+    /// Seconds to wait before failing
+    pub timeout: Option<f32>,
+    elapsed: f32,
+    reported: bool,
+}
+
+impl WaitForTag {
+    pub fn added(tag: TagId) -> Self {
+        Self { tag, present: true, timeout: None, elapsed: 0., reported: false }
+    }
+
+    pub fn removed(tag: TagId) -> Self {
+        Self { tag, present: false, timeout: None, elapsed: 0., reported: false }
+    }
+
+    pub fn with_timeout(mut self, seconds: f32) -> Self {
+        self.timeout = Some(seconds);
+        self
+    }
+}
+
+/// Ends the running ability with an outcome, despawning its tree.
+/// Use it with `Behave::trigger(EndCurrentAbility(AbilityOutcome::Succeeded))`.
+#[derive(Clone)]
+pub struct EndCurrentAbility(pub AbilityOutcome);
+
+pub(crate) fn tick_wait_seconds<T: StatTrait>(
+    mut nodes: Query<(&mut WaitSeconds, &BehaveCtx)>,
+    casters: Query<&ActiveAbilities<T>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    // The task is only despawned once the report is handled, so don't report twice
+    for (mut node, ctx) in nodes.iter_mut().filter(|(node, _)| !node.reported) {
+        let level = casters.get(ctx.target_entity()).ok()
+            .and_then(|active| active.get_by_ctx(ctx))
+            .map_or(1, |ability| ability.level());
+        node.elapsed += time.delta_secs();
+        if node.elapsed >= node.seconds.at(level) {
+            node.reported = true;
+            commands.trigger(ctx.success());
+        }
+    }
+}
+
+pub(crate) fn commit_costs<T: StatTrait>(
+    trigger: Trigger<BehaveTrigger<CommitCosts>>,
+    casters: Query<&ActiveAbilities<T>>,
+    stats: Query<&GameplayStats<T>>,
+    items: Query<&AbilityItems>,
+    mut commands: Commands,
+) {
+    let ctx = trigger.event().ctx();
+    let caster = ctx.target_entity();
+    let active = casters.get(caster).ok();
+    let Some((active, ability)) = active.and_then(|active| Some((active, active.get_by_ctx(ctx)?))) else {
+        commands.trigger(ctx.failure());
+        return
+    };
+    if ability.is_committed() || ability.costs.policy != CostPolicy::OnCommit {
+        commands.trigger(ctx.success());
+        return
+    }
+    let affordable = ability.costs
+        .can_pay(ability.level, stats.get(caster).ok(), items.get(caster).ok(), &active.reserved)
+        .is_ok();
+    if affordable {
+        commands.trigger(CommitAbility{ entity: caster, ability: ability.tags.ability });
+        commands.trigger(ctx.success());
+    } else {
+        commands.trigger(ctx.failure());
+    }
+}
+
+pub(crate) fn add_tags<T: StatTrait>(
+    trigger: Trigger<BehaveTrigger<AddTags>>,
+    mut casters: Query<(&mut ActiveAbilities<T>, &mut ActiveTags, &mut AbilityTagCounts)>,
+    mut commands: Commands,
+) {
+    let ctx = trigger.event().ctx();
+    let AddTags(tags) = trigger.event().inner();
+    let Ok((mut active, mut active_tags, mut tag_counts)) = casters.get_mut(ctx.target_entity()) else {
+        commands.trigger(ctx.failure());
+        return
+    };
+    let Some(ability) = active.get_mut_by_ctx(ctx) else {
+        commands.trigger(ctx.failure());
+        return
+    };
+    // Counted like the ability's own tags, so another ability holding the same tag keeps it
+    for tag in tags.iter() {
+        tag_counts.add(*tag, &mut active_tags);
+        ability.node_tags.push(*tag);
+    }
+    commands.trigger(ctx.success());
+}
+
+pub(crate) fn remove_tags<T: StatTrait>(
+    trigger: Trigger<BehaveTrigger<RemoveTags>>,
+    mut casters: Query<(&mut ActiveAbilities<T>, &mut ActiveTags, &mut AbilityTagCounts)>,
+    mut commands: Commands,
+) {
+    let ctx = trigger.event().ctx();
+    let RemoveTags(tags) = trigger.event().inner();
+    let Ok((mut active, mut active_tags, mut tag_counts)) = casters.get_mut(ctx.target_entity()) else {
+        commands.trigger(ctx.failure());
+        return
+    };
+    let mut node_tags = active.get_mut_by_ctx(ctx).map(|ability| &mut ability.node_tags);
+    for tag in tags.iter() {
+        // Tags from an AddTags node only go once nothing else holds them
+        let added = node_tags.as_ref().and_then(|node_tags| node_tags.iter().position(|t| t == tag));
+        match (node_tags.as_mut(), added) {
+            (Some(node_tags), Some(index)) => {
+                node_tags.remove(index);
+                tag_counts.remove(*tag, &mut active_tags);
+            }
+            _ => active_tags.remove(*tag),
+        }
+    }
+    commands.trigger(ctx.success());
+}
+
+pub(crate) fn check_wait_for_tag(
+    mut nodes: Query<(&mut WaitForTag, &BehaveCtx)>,
+    casters: Query<&ActiveTags>,
+    tag_registry: Res<TagRegistry>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (mut node, ctx) in nodes.iter_mut().filter(|(node, _)| !node.reported) {
+        node.elapsed += time.delta_secs();
+        let result = match casters.get(ctx.target_entity()) {
+            Err(_) => Some(false),
+            Ok(tags) if tags.any_match(node.tag, &tag_registry) == node.present => Some(true),
+            Ok(_) => node.timeout.is_some_and(|timeout| node.elapsed >= timeout).then_some(false),
+        };
+        if let Some(success) = result {
+            node.reported = true;
+            commands.trigger(if success { ctx.success() } else { ctx.failure() });
+        }
+    }
+}
+
+pub(crate) fn end_current_ability<T: StatTrait>(
+    trigger: Trigger<BehaveTrigger<EndCurrentAbility>>,
+    mut ending: AbilityEnding<T>,
+    mut commands: Commands,
+) {
+    let ctx = trigger.event().ctx();
+    let EndCurrentAbility(outcome) = trigger.event().inner();
+    let caster = ctx.target_entity();
+    let instance = ending.active(caster)
+        .and_then(|active| active.get_by_ctx(ctx))
+        .and_then(|ability| ability.instance());
+    let succeeded = *outcome == AbilityOutcome::Succeeded;
+    let Some(ability) = instance.and_then(|instance| ending.stop(caster, instance, succeeded, &mut commands)) else {
+        commands.trigger(ctx.failure());
+        return
+    };
+    // Stopping the ability despawns the tree, so the node doesn't report back.
+    // It is already stopped, so EndAbility only tells everyone else.
+    commands.trigger(EndAbility{ entity: caster, ability, outcome: *outcome });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_behave::prelude::*;
+    use bevy_gameplay_effects::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    fn damage() -> GameplayEffect<TestStats> {
+        GameplayEffect::new(
+            None,
+            TestStats::Health,
+            EffectMagnitude::Fixed(-3.),
+            EffectCalculation::Additive,
+            EffectDuration::Immediate,
+        )
+    }
+
+    fn health(app: &App, entity: Entity) -> f32 {
+        app.world().get::<GameplayStats<TestStats>>(entity).unwrap().get(TestStats::Health).current_value
+    }
+
+    /// An app running the given abilities, with their trees ticking every frame
+    fn node_app(tags: TagRegistry, definitions: impl IntoIterator<Item = AbilityDefinition<TestStats>>) -> App {
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        definitions.into_iter().for_each(|definition| abilities.register(definition));
+        let mut app = app(tags, abilities);
+        app.add_plugins(BehavePlugin::new(Update));
+        record(&mut app, |e: &EndAbility<TestStats>| e.outcome);
+        app
+    }
+
+    fn with_tree(tag: TagId, tree: Tree<Behave>) -> AbilityDefinition<TestStats> {
+        AbilityDefinition::new(tag).with_execution_tree(tree)
+    }
+
+    fn ended(app: &App) -> Vec<AbilityOutcome> {
+        recorded::<EndAbility<TestStats>, AbilityOutcome>(app)
+    }
+
+    fn run(app: &mut App, caster: Entity, tag: TagId) {
+        try_execute(app, caster, tag);
+        (0..4).for_each(|_| update(app));
+    }
+
+    fn execute_at(app: &mut App, caster: Entity, tag: TagId, target_data: TargetData) {
+        let ability = ability(app, tag).with_target_data(target_data);
+        trigger(app, TryExecuteAbility { entity: caster, ability });
+        (0..4).for_each(|_| update(app));
+    }
+
+    fn spawn_target(app: &mut App, position: Vec3, tags: &[TagId]) -> Entity {
+        let mut active_tags = ActiveTags::default();
+        tags.iter().for_each(|t| active_tags.push(*t));
+        app.world_mut().spawn((active_tags, GlobalTransform::from_translation(position), test_stats())).id()
+    }
+
+    #[test]
+    fn effects_reach_every_entity_target_that_can_take_them() {
+        let mut tags = TagRegistry::new();
+        let strike = tags.register("Ability.Strike");
+        let node = ApplyEffectToTargets::new(damage());
+        let mut app = node_app(tags, [with_tree(strike, tree! { Behave::trigger(node) })]);
+        record(&mut app, |e: &ApplyEffectResult| (e.target, e.success));
+        let caster = spawn_caster(&mut app, [strike]);
+        let target = spawn_target(&mut app, Vec3::ZERO, &[]);
+        let statless = app.world_mut().spawn(ActiveTags::default()).id();
+
+        execute_at(&mut app, caster, strike, TargetData::Entities([target, statless].into_iter().collect()));
+        assert_eq!(recorded::<ApplyEffectResult, (Entity, bool)>(&app), vec![(target, true), (statless, false)]);
+        assert_eq!(health(&app, target), 7.);
+        assert_eq!(ended(&app), vec![AbilityOutcome::Succeeded]);
+    }
+
+    #[test]
+    fn require_all_fails_when_a_target_is_skipped() {
+        let mut tags = TagRegistry::new();
+        let strike = tags.register("Ability.Strike");
+        let ally = tags.register("Team.Ally");
+        let node = ApplyEffectToTargets::new(damage()).with_filter(TargetFilter::new().blocked([ally])).require_all();
+        let mut app = node_app(tags, [with_tree(strike, tree! { Behave::trigger(node) })]);
+        let caster = spawn_caster(&mut app, [strike]);
+        let enemy = spawn_target(&mut app, Vec3::ZERO, &[]);
+        let friend = spawn_target(&mut app, Vec3::ZERO, &[ally]);
+
+        execute_at(&mut app, caster, strike, TargetData::Entities([enemy, friend].into_iter().collect()));
+        assert_eq!(health(&app, enemy), 7.);
+        assert_eq!(health(&app, friend), 10.);
+        assert_eq!(ended(&app), vec![AbilityOutcome::Failed]);
+    }
+
+    #[test]
+    fn point_targets_hit_filtered_entities_in_the_radius() {
+        let mut tags = TagRegistry::new();
+        let blast = tags.register("Ability.Blast");
+        let ally = tags.register("Team.Ally");
+        let node = ApplyEffectToTargets::new(damage()).with_radius(2.).with_filter(TargetFilter::new().blocked([ally]));
+        let mut app = node_app(tags, [with_tree(blast, tree! { Behave::trigger(node) })]);
+        record(&mut app, |e: &ApplyEffectResult| e.target);
+        let caster = spawn_caster(&mut app, [blast]);
+        let near = spawn_target(&mut app, Vec3::new(5., 0., 1.), &[]);
+        let friend = spawn_target(&mut app, Vec3::new(5., 0., -1.), &[ally]);
+        let far = spawn_target(&mut app, Vec3::new(5., 0., 4.), &[]);
+
+        execute_at(&mut app, caster, blast, TargetData::Point(Vec3::X * 5.));
+        assert_eq!(recorded::<ApplyEffectResult, Entity>(&app), vec![near]);
+        assert_eq!([health(&app, near), health(&app, friend), health(&app, far)], [7., 10., 10.]);
+    }
+
+    #[test]
+    fn radius_hits_skip_the_caster_unless_included() {
+        let mut tags = TagRegistry::new();
+        let blast = tags.register("Ability.Blast");
+        let nova = tags.register("Ability.Nova");
+        let mut app = node_app(tags, [
+            with_tree(blast, tree! { Behave::trigger(ApplyEffectToTargets::new(damage()).with_radius(2.)) }),
+            with_tree(nova, tree! { Behave::trigger(ApplyEffectToTargets::new(damage()).with_radius(2.).include_caster()) }),
+        ]);
+        let caster = spawn_caster(&mut app, [blast, nova]);
+        app.world_mut().entity_mut(caster).insert(GlobalTransform::default());
+        let target = spawn_target(&mut app, Vec3::X, &[]);
+
+        execute_at(&mut app, caster, blast, TargetData::Point(Vec3::ZERO));
+        assert_eq!([health(&app, caster), health(&app, target)], [10., 7.]);
+        execute_at(&mut app, caster, nova, TargetData::Point(Vec3::ZERO));
+        assert_eq!([health(&app, caster), health(&app, target)], [7., 4.]);
+    }
+
+    #[test]
+    fn point_targets_without_a_radius_fail() {
+        let mut tags = TagRegistry::new();
+        let blast = tags.register("Ability.Blast");
+        let mut app = node_app(tags, [with_tree(blast, tree! { Behave::trigger(ApplyEffectToTargets::new(damage())) })]);
+        record(&mut app, |e: &ApplyEffectResult| e.target);
+        let caster = spawn_caster(&mut app, [blast]);
+        let target = spawn_target(&mut app, Vec3::ZERO, &[]);
+
+        execute_at(&mut app, caster, blast, TargetData::Point(Vec3::ZERO));
+        assert!(recorded::<ApplyEffectResult, Entity>(&app).is_empty());
+        assert_eq!(health(&app, target), 10.);
+        assert_eq!(ended(&app), vec![AbilityOutcome::Failed]);
+    }
+
+    #[test]
+    fn wait_seconds_scale_with_level_and_report_once() {
+        let mut tags = TagRegistry::new();
+        let focus = tags.register("Ability.Focus");
+        let wait = WaitSeconds::new(ScalableValue::Linear { base: 1., per_level: 1. });
+        let mut app = node_app(tags, [with_tree(focus, tree! { Behave::spawn(wait) })]);
+        record(&mut app, |_: &BehaveStatusReport| ());
+        let caster = spawn_caster(&mut app, []);
+        app.world_mut().entity_mut(caster).insert(GrantedAbilities::<TestStats>::innate_with_levels([(focus, 2)]));
+
+        run(&mut app, caster, focus);
+        tick(&mut app, 1.5);
+        assert_eq!(running(&app, caster), 1);
+        tick(&mut app, 1.);
+        (0..3).for_each(|_| tick(&mut app, 1.));
+        assert_eq!(ended(&app), vec![AbilityOutcome::Succeeded]);
+        assert_eq!(recorded::<BehaveStatusReport, ()>(&app).len(), 1);
+    }
+
+    #[test]
+    fn commit_costs_pays_once_and_fails_when_it_cannot() {
+        let mut tags = TagRegistry::new();
+        let bolt = tags.register("Ability.Bolt");
+        let nova = tags.register("Ability.Nova");
+        let commit_twice = tree! { Behave::Sequence => {
+            Behave::trigger(CommitCosts),
+            Behave::trigger(CommitCosts),
+        }};
+        let commit_later = tree! { Behave::Sequence => {
+            Behave::spawn(WaitSeconds::new(1.)),
+            Behave::trigger(CommitCosts),
+        }};
+        let mut app = node_app(tags, [
+            with_tree(bolt, commit_twice)
+                .with_stat_cost(StatCost::new(TestStats::Mana, 4.))
+                .with_cost_policy(CostPolicy::OnCommit),
+            with_tree(nova, commit_later)
+                .with_stat_cost(StatCost::new(TestStats::Mana, 8.))
+                .with_cost_policy(CostPolicy::OnCommit),
+        ]);
+        let caster = spawn_caster(&mut app, [bolt, nova]);
+
+        // Nova can pay when it starts, but not once bolt has spent some mana
+        run(&mut app, caster, nova);
+        run(&mut app, caster, bolt);
+        assert_eq!(mana(&app, caster), 6.);
+        (0..4).for_each(|_| tick(&mut app, 1.));
+        assert_eq!(mana(&app, caster), 6.);
+        assert_eq!(ended(&app), vec![AbilityOutcome::Succeeded, AbilityOutcome::Failed]);
+    }
+
+    #[test]
+    fn added_tags_are_shared_with_other_abilities() {
+        let mut tags = TagRegistry::new();
+        let guard = tags.register("Ability.Guard");
+        let brace = tags.register("Ability.Brace");
+        let busy = tags.register("Character.State.Busy");
+        let go = tags.register("Character.State.Go");
+        let tree = tree! { Behave::Sequence => {
+            Behave::trigger(AddTags::new([busy])),
+            Behave::spawn(WaitForTag::added(go)),
+        }};
+        let mut app = node_app(tags, [with_tree(guard, tree), AbilityDefinition::new(brace).adds_tags([busy])]);
+        let caster = spawn_caster(&mut app, [guard, brace]);
+
+        run(&mut app, caster, guard);
+        assert!(has_tag(&app, caster, busy));
+        run(&mut app, caster, brace);
+        trigger(&mut app, CancelAbility { entity: caster, ability: guard });
+        assert!(has_tag(&app, caster, busy));
+        trigger(&mut app, CancelAbility { entity: caster, ability: brace });
+        assert!(!has_tag(&app, caster, busy));
+    }
+
+    #[test]
+    fn removed_tags_stay_while_another_ability_holds_them() {
+        let mut tags = TagRegistry::new();
+        let guard = tags.register("Ability.Guard");
+        let brace = tags.register("Ability.Brace");
+        let busy = tags.register("Character.State.Busy");
+        let rooted = tags.register("Character.State.Rooted");
+        let go = tags.register("Character.State.Go");
+        let tree = tree! { Behave::Sequence => {
+            Behave::trigger(AddTags::new([busy, rooted])),
+            Behave::trigger(RemoveTags::new([busy, rooted])),
+            Behave::spawn(WaitForTag::added(go)),
+        }};
+        let mut app = node_app(tags, [with_tree(guard, tree), AbilityDefinition::new(brace).adds_tags([busy])]);
+        let caster = spawn_caster(&mut app, [guard, brace]);
+
+        run(&mut app, caster, brace);
+        run(&mut app, caster, guard);
+        assert_eq!(running(&app, caster), 2);
+        assert!(has_tag(&app, caster, busy));
+        assert!(!has_tag(&app, caster, rooted));
+    }
+
+    #[test]
+    fn wait_for_tag_succeeds_on_the_tag_or_fails_on_timeout() {
+        let mut tags = TagRegistry::new();
+        let parry = tags.register("Ability.Parry");
+        let hit = tags.register("Event.Hit");
+        let wait = WaitForTag::added(hit).with_timeout(1.);
+        let mut app = node_app(tags, [with_tree(parry, tree! { Behave::spawn(wait) })]);
+        let struck = spawn_caster(&mut app, [parry]);
+        let missed = spawn_caster(&mut app, [parry]);
+
+        run(&mut app, struck, parry);
+        run(&mut app, missed, parry);
+        app.world_mut().get_mut::<ActiveTags>(struck).unwrap().push(hit);
+        (0..3).for_each(|_| update(&mut app));
+        assert_eq!(ended(&app), vec![AbilityOutcome::Succeeded]);
+        assert_eq!(running(&app, missed), 1);
+        (0..4).for_each(|_| tick(&mut app, 0.5));
+        assert_eq!(ended(&app), vec![AbilityOutcome::Succeeded, AbilityOutcome::Failed]);
+    }
+
+    #[test]
+    fn end_current_ability_ends_it_once_with_the_outcome() {
+        let mut tags = TagRegistry::new();
+        let feint = tags.register("Ability.Feint");
+        let busy = tags.register("Character.State.Busy");
+        let tree = tree! { Behave::Sequence => {
+            Behave::trigger(AddTags::new([busy])),
+            Behave::trigger(EndCurrentAbility(AbilityOutcome::Interrupted)),
+        }};
+        let mut app = node_app(tags, [with_tree(feint, tree)]);
+        let caster = spawn_caster(&mut app, [feint]);
+
+        run(&mut app, caster, feint);
+        (0..3).for_each(|_| update(&mut app));
+        assert_eq!(ended(&app), vec![AbilityOutcome::Interrupted]);
+        assert_eq!(running(&app, caster), 0);
+        assert!(!has_tag(&app, caster, busy));
+    }
+}
@@ -0,0 +1,146 @@
+use bevy::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::SmallVec;
+use crate::{
+    ability::{ability_tags_ok, AbilityActivation},
+    prelude::*,
+};
+
+
+/// Remembers which granted passives have started, so resuming one after it
+/// was suspended doesn't pay its costs again.
+/// This is added automatically with ActiveAbilities.
+#[derive(Component, Default)]
+pub struct PassiveState(SmallVec<[TagId; 2]>);
+
+impl PassiveState {
+    /// True if the passive has started since it was granted, whether it is running or suspended
+    pub fn started(&self, ability: TagId) -> bool {
+        self.0.contains(&ability)
+    }
+}
+
+/// Starts granted passive abilities, suspends them while their tags don't allow them,
+/// and stops them once they are revoked
+pub(crate) fn update_passive_abilities<T: StatTrait>(
+    mut q: Query<(Entity, &mut PassiveState)>,
+    mut activation: AbilityActivation<T>,
+    registry: Res<AbilityRegistry<T>>,
+    tag_registry: Res<TagRegistry>,
+    mut commands: Commands,
+) {
+    for (entity, mut state) in q.iter_mut() {
+        let Some((active_tags, granted, active)) = activation.caster(entity) else { continue };
+
+        // Revoked or blocked passives are stopped
+        for running in active.iter() {
+            if running.activation != ActivationPolicy::Passive { continue }
+            let allowed = granted.contains(running.tags.ability)
+                && ability_tags_ok(&running.tags, &tag_registry, active_tags).is_ok();
+            if !allowed {
+                let Some(instance) = running.instance() else { continue };
+                commands.trigger(CancelAbilityInstance{ entity, instance });
+            }
+        }
+        if state.0.iter().any(|tag| !granted.contains(*tag)) {
+            state.0.retain(|tag| granted.contains(*tag));
+        }
+
+        // Granted passives that are allowed to run are started.  The tags are checked against
+        // the definition first so a suspended passive isn't built or reported every frame.
+        let ready: SmallVec<[&AbilityDefinition<T>; 2]> = granted.iter()
+            .filter(|(tag, _)| !active.is_active(*tag))
+            .filter_map(|(tag, _)| registry.get(&tag))
+            .filter(|d| d.activation == ActivationPolicy::Passive)
+            .filter(|d| ability_tags_ok(&d.tags, &tag_registry, active_tags).is_ok())
+            .collect();
+        for definition in ready {
+            let tag = definition.tags.ability;
+            if state.started(tag) {
+                activation.resume(entity, Ability::from(definition), &mut commands);
+            } else if activation.try_execute(entity, Ability::from(definition), &mut commands).is_ok() {
+                state.0.push(tag);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    fn aura(tags: &mut TagRegistry) -> (TagId, TagId, AbilitiesPlugin<TestStats>) {
+        let aura = tags.register("Ability.Aura");
+        let silenced = tags.register("Character.State.Silenced");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(aura)
+            .with_activation_policy(ActivationPolicy::Passive)
+            .blocked_by([silenced])
+            .with_stat_cost(StatCost::new(TestStats::Mana, 4.))
+            .with_cost_policy(CostPolicy::OnActivate)
+            .with_cooldown(30.)
+        );
+        (aura, silenced, abilities)
+    }
+
+    #[test]
+    fn passives_resume_without_paying_again() {
+        let mut tags = TagRegistry::new();
+        let (aura, silenced, abilities) = aura(&mut tags);
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [aura]);
+
+        update(&mut app);
+        assert_eq!(running(&app, caster), 1);
+        assert_eq!(mana(&app, caster), 6.);
+
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(silenced);
+        update(&mut app);
+        assert_eq!(running(&app, caster), 0);
+        assert!(app.world().get::<PassiveState>(caster).unwrap().started(aura));
+
+        // Still on cooldown, but resuming isn't a new activation
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(silenced);
+        update(&mut app);
+        assert_eq!(running(&app, caster), 1);
+        assert_eq!(mana(&app, caster), 6.);
+    }
+
+    #[test]
+    fn suspended_passives_are_not_reported_as_failures() {
+        let mut tags = TagRegistry::new();
+        let (aura, silenced, abilities) = aura(&mut tags);
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| e.ability);
+        let caster = spawn_caster(&mut app, [aura]);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(silenced);
+
+        update(&mut app);
+        update(&mut app);
+        assert_eq!(running(&app, caster), 0);
+        assert!(!app.world().get::<PassiveState>(caster).unwrap().started(aura));
+        assert!(recorded::<AbilityActivationFailed<TestStats>, TagId>(&app).is_empty());
+    }
+
+    #[test]
+    fn revoked_passives_stop_and_pay_again_when_granted() {
+        let mut tags = TagRegistry::new();
+        let (aura, _, abilities) = aura(&mut tags);
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [aura]);
+
+        update(&mut app);
+        app.world_mut().get_mut::<GrantedAbilities<TestStats>>(caster).unwrap().revoke(aura);
+        update(&mut app);
+        assert_eq!(running(&app, caster), 0);
+        assert!(!app.world().get::<PassiveState>(caster).unwrap().started(aura));
+
+        tick(&mut app, 31.);
+        app.world_mut().get_mut::<GrantedAbilities<TestStats>>(caster).unwrap().grant(aura, 1);
+        update(&mut app);
+        assert_eq!(running(&app, caster), 1);
+        assert_eq!(mana(&app, caster), 2.);
+    }
+}
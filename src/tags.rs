@@ -1,12 +1,9 @@
 use bevy_hierarchical_tags::prelude::*;
-use bevy::prelude::*;
+use bevy::{platform::collections::HashMap, prelude::*};
 
 
 
 
-#[derive(Component, Clone, Deref, DerefMut)]
-pub struct CurrentAbility(Option<TagId>);
-
 // Per ability tags
 #[derive(Clone)]
 pub struct AbilityTags {
@@ -43,4 +40,55 @@ pub struct CancelTags(TagList<4>);
 
 /// These tags are added to an entity when the ability executes
 #[derive(Clone, Deref, DerefMut, Default)]
-pub struct AbilityAddTags(TagList<2>);
\ No newline at end of file
+pub struct AbilityAddTags(TagList<2>);
+
+/// How many running abilities added each tag.  Several abilities can add the same tag,
+/// so it is only removed from ActiveTags once the last of them stops.
+#[derive(Component, Default)]
+pub struct AbilityTagCounts(HashMap<TagId, u16>);
+
+impl AbilityTagCounts {
+    /// How many running abilities are holding this tag
+    pub fn count(&self, tag: TagId) -> u16 {
+        self.0.get(&tag).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn add(&mut self, tag: TagId, active_tags: &mut ActiveTags) {
+        let count = self.0.entry(tag).or_default();
+        *count += 1;
+        if *count == 1 {
+            active_tags.push(tag);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, tag: TagId, active_tags: &mut ActiveTags) {
+        let Some(count) = self.0.get_mut(&tag) else { return };
+        *count -= 1;
+        if *count == 0 {
+            self.0.remove(&tag);
+            active_tags.remove(tag);
+        }
+    }
+}
+
+/// True if `tag` is `parent` itself or one of its children in the tag hierarchy
+pub(crate) fn tag_matches(tag: TagId, parent: TagId, registry: &TagRegistry) -> bool {
+    tag == parent || registry.is_descendant(tag, parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_match_themselves_and_their_children() {
+        let mut registry = TagRegistry::new();
+        let grenade = registry.register("Ability.Grenade");
+        let throwing = registry.register("Ability.Grenade.Throwing");
+        let stun = registry.register("Ability.Stun");
+        assert!(tag_matches(grenade, grenade, &registry));
+        assert!(tag_matches(throwing, grenade, &registry));
+        assert!(!tag_matches(grenade, throwing, &registry));
+        assert!(!tag_matches(stun, grenade, &registry));
+    }
+}
@@ -0,0 +1,129 @@
+use std::{any::Any, sync::Arc};
+use bevy::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::SmallVec;
+use crate::prelude::*;
+
+
+/// What a running ability is aimed at.
+/// Supply it when activating, or fill it in later with SetTargetData.
+#[derive(Clone, Default)]
+pub enum TargetData {
+    #[default]
+    None,
+    Point(Vec3),
+    Direction(Vec3),
+    Entity(Entity),
+    Entities(SmallVec<[Entity; 8]>),
+    /// Anything else, made with TargetData::custom.  Read it back with TargetData::get_custom.
+    Custom(Arc<dyn Any + Send + Sync>),
+}
+
+impl TargetData {
+    pub fn custom<C: Any + Send + Sync>(data: C) -> Self {
+        Self::Custom(Arc::new(data))
+    }
+
+    pub fn is_none(&self) -> bool {
+        matches!(self, Self::None)
+    }
+
+    pub fn point(&self) -> Option<Vec3> {
+        match self {
+            Self::Point(point) => Some(*point),
+            _ => None,
+        }
+    }
+
+    pub fn direction(&self) -> Option<Vec3> {
+        match self {
+            Self::Direction(direction) => Some(*direction),
+            _ => None,
+        }
+    }
+
+    /// The first targeted entity
+    pub fn entity(&self) -> Option<Entity> {
+        self.entities().first().copied()
+    }
+
+    /// Every targeted entity.  Empty unless the target is Entity or Entities.
+    pub fn entities(&self) -> &[Entity] {
+        match self {
+            Self::Entity(entity) => std::slice::from_ref(entity),
+            Self::Entities(entities) => entities,
+            _ => &[],
+        }
+    }
+
+    pub fn get_custom<C: Any + Send + Sync>(&self) -> Option<&C> {
+        match self {
+            Self::Custom(data) => data.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces the target data of a running ability
+pub(crate) fn set_target_data<T: StatTrait>(
+    trigger: Trigger<SetTargetData>,
+    mut active: Query<&mut ActiveAbilities<T>>,
+) {
+    let SetTargetData{ entity, instance, data } = trigger.event();
+    let Ok(mut active) = active.get_mut(*entity) else { return };
+    let Some(ability) = active.get_mut(*instance) else { return };
+    ability.target_data = data.clone();
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    #[derive(PartialEq, Debug)]
+    struct Waypoints(Vec<Vec3>);
+
+    #[test]
+    fn accessors_only_read_their_own_variant() {
+        let mut world = World::new();
+        let [a, b] = [world.spawn_empty().id(), world.spawn_empty().id()];
+
+        let entities = TargetData::Entities([a, b].into_iter().collect());
+        assert_eq!(entities.entity(), Some(a));
+        assert_eq!(entities.entities(), &[a, b]);
+        assert_eq!(entities.point(), None);
+        assert_eq!(TargetData::Entity(b).entities(), &[b]);
+        assert_eq!(TargetData::Point(Vec3::X).point(), Some(Vec3::X));
+        assert_eq!(TargetData::Point(Vec3::X).direction(), None);
+        assert!(TargetData::None.entities().is_empty());
+
+        let custom = TargetData::custom(Waypoints(vec![Vec3::Y]));
+        assert_eq!(custom.get_custom::<Waypoints>(), Some(&Waypoints(vec![Vec3::Y])));
+        assert_eq!(custom.get_custom::<Vec3>(), None);
+        assert_eq!(TargetData::Direction(Vec3::Z).get_custom::<Waypoints>(), None);
+    }
+
+    #[test]
+    fn target_data_is_passed_on_activation_and_replaced_by_set_target_data() {
+        let mut tags = TagRegistry::new();
+        let blink = tags.register("Ability.Blink");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(blink));
+        let mut app = app(tags, abilities);
+        let caster = spawn_caster(&mut app, [blink]);
+        let point = |app: &App| {
+            let active = app.world().get::<ActiveAbilities<TestStats>>(caster).unwrap();
+            active.get_by_tag(blink).and_then(|a| a.target_data.point())
+        };
+
+        app.world_mut().commands().entity(caster).try_activate_ability_at(blink, TargetData::Point(Vec3::X));
+        update(&mut app);
+        assert_eq!(point(&app), Some(Vec3::X));
+
+        let instance = app.world().get::<ActiveAbilities<TestStats>>(caster).unwrap()
+            .get_by_tag(blink).and_then(|a| a.instance()).unwrap();
+        trigger(&mut app, SetTargetData{ entity: caster, instance, data: TargetData::Point(Vec3::Y) });
+        assert_eq!(point(&app), Some(Vec3::Y));
+    }
+}
@@ -0,0 +1,438 @@
+use bevy::{ecs::system::{StaticSystemParam, SystemParam, SystemParamItem}, prelude::*};
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use smallvec::SmallVec;
+use crate::{
+    area::{AbilityAreaQuery, AreaShape},
+    costs::{AbilityItems, CostPayer},
+    prelude::*,
+};
+
+
+/// Which entities a targeting mode is allowed to pick, checked against their ActiveTags
+#[derive(Clone, Default)]
+pub struct TargetFilter {
+    /// The target must have all of these, e.g. Team.Enemy
+    pub required: SmallVec<[TagId; 2]>,
+    /// The target must have none of these, e.g. State.Invulnerable
+    pub blocked: SmallVec<[TagId; 2]>,
+}
+
+impl TargetFilter {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn required(mut self, tags: impl IntoIterator<Item = TagId>) -> Self {
+        self.required.extend(tags);
+        self
+    }
+
+    pub fn blocked(mut self, tags: impl IntoIterator<Item = TagId>) -> Self {
+        self.blocked.extend(tags);
+        self
+    }
+
+    /// Entities without ActiveTags only pass a filter with no required tags
+    pub fn matches(&self, tags: Option<&ActiveTags>, registry: &TagRegistry) -> bool {
+        let Some(tags) = tags else { return self.required.is_empty() };
+        self.required.iter().all(|t| tags.any_match(*t, registry))
+            && !self.blocked.iter().any(|t| tags.any_match(*t, registry))
+    }
+}
+
+/// How an ability picks its target before it starts.
+/// Entities can only be targeted if they have ActiveTags.
+#[derive(Clone)]
+pub enum TargetingMode {
+    /// Targets the caster without waiting for confirmation
+    SelfTarget,
+    /// One entity within range.  Confirm with TargetData::Entity.
+    Entity { range: f32, filter: TargetFilter },
+    /// A point within range, e.g. for ground effects.  Confirm with TargetData::Point.
+    Point { range: f32 },
+    /// Every entity in a cone in front of the caster.  Confirm with a TargetData::Direction,
+    /// or a TargetData::Point to aim at.  `half_angle` is in radians.
+    Cone { range: f32, half_angle: f32, filter: TargetFilter },
+    /// Every entity within radius of the caster.  Confirm with any TargetData.
+    Radius { radius: f32, filter: TargetFilter },
+}
+
+/// Why a ConfirmTarget was rejected
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TargetRejectedReason {
+    /// The confirmed TargetData doesn't fit the targeting mode
+    WrongKind,
+    OutOfRange,
+    /// The target doesn't pass the mode's TargetFilter
+    Filtered,
+    /// The TargetValidator found something in the way
+    NoLineOfSight,
+    /// The target can't be used for any other reason, e.g. the caster has no GlobalTransform
+    Invalid,
+}
+
+/// Extra checks on a confirmed target, e.g. a raycast for line of sight.
+/// Register one with AbilitiesPlugin::set_target_validator.
+pub trait TargetValidator: Send + Sync + 'static {
+    type Param: SystemParam;
+
+    /// Return the reason to reject the target with, usually NoLineOfSight
+    fn validate(
+        param: &SystemParamItem<Self::Param>,
+        caster: Entity,
+        origin: Vec3,
+        target: &TargetData,
+        positions: &[Vec3],
+    ) -> Result<(), TargetRejectedReason>;
+}
+
+/// Accepts every target
+impl TargetValidator for () {
+    type Param = ();
+
+    fn validate(
+        _: &SystemParamItem<Self::Param>,
+        _: Entity,
+        _: Vec3,
+        _: &TargetData,
+        _: &[Vec3],
+    ) -> Result<(), TargetRejectedReason> {
+        Ok(())
+    }
+}
+
+/// Checks a ConfirmTarget against the waiting ability's targeting mode and the validator
+pub(crate) fn confirm_target<T: StatTrait, V: TargetValidator>(
+    trigger: Trigger<ConfirmTarget>,
+    casters: Query<&ActiveAbilities<T>>,
+    area: AbilityAreaQuery,
+    validator: StaticSystemParam<V::Param>,
+    mut commands: Commands,
+) {
+    let ConfirmTarget{ entity, ability, data } = trigger.event();
+    let Ok(active) = casters.get(*entity) else { return };
+    let Some(waiting) = active.iter().find(|a| a.tags.ability == *ability && a.is_targeting()) else { return };
+    let (Some(instance), Some(mode)) = (waiting.instance(), waiting.targeting.as_ref()) else { return };
+
+    let result = match area.get(*entity) {
+        Some((caster, _)) => resolve_target(mode, *entity, caster, data, &area)
+            .and_then(|(target, positions)| {
+                V::validate(&*validator, *entity, caster.translation(), &target, &positions).map(|_| target)
+            }),
+        None => Err(TargetRejectedReason::Invalid),
+    };
+    match result {
+        Ok(data) => commands.trigger(TargetConfirmed{ entity: *entity, instance, data }),
+        Err(reason) => commands.trigger(TargetRejected{ entity: *entity, ability: *ability, reason }),
+    }
+}
+
+/// Turns what the player picked into the ability's target data, and the positions to validate
+fn resolve_target(
+    mode: &TargetingMode,
+    caster: Entity,
+    transform: &GlobalTransform,
+    data: &TargetData,
+    area: &AbilityAreaQuery,
+) -> Result<(TargetData, SmallVec<[Vec3; 8]>), TargetRejectedReason> {
+    use TargetRejectedReason::*;
+    let origin = transform.translation();
+    let in_area = |shape: AreaShape, filter: &TargetFilter| {
+        let mut entities = area.query(&shape, filter);
+        entities.retain(|e| *e != caster);
+        let positions = entities.iter()
+            .filter_map(|e| area.get(*e))
+            .map(|(transform, _)| transform.translation())
+            .collect();
+        (TargetData::Entities(entities), positions)
+    };
+
+    match mode {
+        TargetingMode::SelfTarget => Ok((TargetData::Entity(caster), SmallVec::new())),
+        TargetingMode::Entity { range, filter } => {
+            let Some(target) = data.entity() else { return Err(WrongKind) };
+            let Some((target_transform, _)) = area.get(target) else { return Err(WrongKind) };
+            let position = target_transform.translation();
+            if origin.distance(position) > *range { return Err(OutOfRange) }
+            if !area.matches(target, filter) { return Err(Filtered) }
+            Ok((TargetData::Entity(target), SmallVec::from_slice(&[position])))
+        }
+        TargetingMode::Point { range } => {
+            let Some(point) = data.point() else { return Err(WrongKind) };
+            if origin.distance(point) > *range { return Err(OutOfRange) }
+            Ok((TargetData::Point(point), SmallVec::from_slice(&[point])))
+        }
+        TargetingMode::Cone { range, half_angle, filter } => {
+            let forward = transform.forward().as_vec3();
+            let direction = match data {
+                TargetData::Direction(direction) => *direction,
+                TargetData::Point(point) => *point - origin,
+                TargetData::None => forward,
+                _ => return Err(WrongKind),
+            }.normalize_or(forward);
+            let shape = AreaShape::Cone { origin, direction, range: *range, half_angle: *half_angle };
+            Ok(in_area(shape, filter))
+        }
+        TargetingMode::Radius { radius, filter } => {
+            Ok(in_area(AreaShape::Sphere { center: origin, radius: *radius }, filter))
+        }
+    }
+}
+
+/// Stores the confirmed target on the ability and lets it carry on with its cast or execution tree.
+/// Abilities without a cast time are committed here, so canceling the targeting costs nothing.
+pub(crate) fn target_confirmed<T: StatTrait>(
+    trigger: Trigger<TargetConfirmed>,
+    mut q: Query<(&mut ActiveAbilities<T>, &mut ActiveTags, &mut AbilityTagCounts, &mut AbilityCooldowns, &mut AbilityCharges)>,
+    stats: Query<&GameplayStats<T>>,
+    mut items: Query<&mut AbilityItems>,
+    mut commands: Commands,
+) {
+    let TargetConfirmed{ entity, instance, data } = trigger.event();
+    let Ok((mut active, mut tags, mut tag_counts, mut cooldowns, mut charges)) = q.get_mut(*entity) else { return };
+    let ActiveAbilities { running, reserved, .. } = &mut *active;
+    let Some(ability) = running.iter_mut().find(|a| a.instance() == Some(*instance)) else { return };
+    if !ability.is_targeting() { return }
+    ability.target_data = data.clone();
+    ability.awaiting_target = false;
+    if !ability.is_committed() && ability.costs.policy != CostPolicy::OnCommit && ability.cast_time.is_none() {
+        let mut items = items.get_mut(*entity).ok();
+        let payer = CostPayer { stats: stats.get(*entity).ok(), items: items.as_deref_mut(), reserved };
+        if !ability.try_commit(*entity, payer, &mut cooldowns, &mut charges, &mut tags, &mut commands) {
+            return;
+        }
+    }
+    ability.begin(*entity, &mut tags, &mut tag_counts, &mut commands);
+}
+
+/// Cancels an ability that is waiting for its target
+pub(crate) fn cancel_target<T: StatTrait>(
+    trigger: Trigger<CancelTarget>,
+    active: Query<&ActiveAbilities<T>>,
+    mut commands: Commands,
+) {
+    let CancelTarget{ entity, ability } = trigger.event();
+    let Ok(active) = active.get(*entity) else { return };
+    let waiting = active.iter()
+        .filter(|a| a.tags.ability == *ability && a.is_targeting())
+        .filter_map(|a| a.instance());
+    for instance in waiting {
+        commands.trigger(CancelAbilityInstance{ entity: *entity, instance });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{ecs::system::{RunSystemOnce, SystemParamItem}, prelude::*};
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{area::AbilityAreaQuery, prelude::*, testing::*};
+    use super::resolve_target;
+
+    fn spawn_at(world: &mut World, position: Vec3, tags: &[TagId]) -> Entity {
+        let mut active_tags = ActiveTags::default();
+        tags.iter().for_each(|t| active_tags.push(*t));
+        world.spawn((GlobalTransform::from_translation(position), active_tags)).id()
+    }
+
+    /// What resolve_target picked, or why it rejected the target
+    fn resolve(world: &mut World, caster: Entity, mode: TargetingMode, data: TargetData) -> Result<Vec<Entity>, TargetRejectedReason> {
+        world.run_system_once(move |area: AbilityAreaQuery| {
+            let (transform, _) = area.get(caster).unwrap();
+            resolve_target(&mode, caster, transform, &data, &area).map(|(target, _)| match target {
+                TargetData::Point(_) => vec![caster],
+                target => target.entities().to_vec(),
+            })
+        }).unwrap()
+    }
+
+    #[test]
+    fn entity_targets_must_be_in_range_and_pass_the_filter() {
+        let mut tags = TagRegistry::new();
+        let enemy = tags.register("Team.Enemy");
+        let mut world = World::new();
+        world.insert_resource(tags);
+        let caster = spawn_at(&mut world, Vec3::ZERO, &[]);
+        let near = spawn_at(&mut world, Vec3::X * 2., &[enemy]);
+        let far = spawn_at(&mut world, Vec3::X * 20., &[enemy]);
+        let friend = spawn_at(&mut world, Vec3::X, &[]);
+        let mode = TargetingMode::Entity { range: 5., filter: TargetFilter::new().required([enemy]) };
+
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::Entity(near)), Ok(vec![near]));
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::Entity(far)), Err(TargetRejectedReason::OutOfRange));
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::Entity(friend)), Err(TargetRejectedReason::Filtered));
+        assert_eq!(resolve(&mut world, caster, mode, TargetData::Point(Vec3::X)), Err(TargetRejectedReason::WrongKind));
+    }
+
+    #[test]
+    fn point_targets_must_be_in_range() {
+        let mut world = World::new();
+        world.insert_resource(TagRegistry::new());
+        let caster = spawn_at(&mut world, Vec3::ZERO, &[]);
+        let mode = TargetingMode::Point { range: 5. };
+
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::Point(Vec3::Z * 4.)), Ok(vec![caster]));
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::Point(Vec3::Z * 6.)), Err(TargetRejectedReason::OutOfRange));
+        assert_eq!(resolve(&mut world, caster, mode, TargetData::Direction(Vec3::Z)), Err(TargetRejectedReason::WrongKind));
+    }
+
+    #[test]
+    fn cones_aim_where_they_are_told_and_skip_the_caster() {
+        let mut world = World::new();
+        world.insert_resource(TagRegistry::new());
+        let caster = spawn_at(&mut world, Vec3::ZERO, &[]);
+        // The caster faces -Z
+        let ahead = spawn_at(&mut world, Vec3::NEG_Z * 3., &[]);
+        let right = spawn_at(&mut world, Vec3::X * 3., &[]);
+        let mode = TargetingMode::Cone { range: 5., half_angle: 0.5, filter: TargetFilter::new() };
+
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::None), Ok(vec![ahead]));
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::Direction(Vec3::X)), Ok(vec![right]));
+        assert_eq!(resolve(&mut world, caster, mode.clone(), TargetData::Point(Vec3::X * 10.)), Ok(vec![right]));
+        assert_eq!(resolve(&mut world, caster, mode, TargetData::Entity(right)), Err(TargetRejectedReason::WrongKind));
+    }
+
+    #[test]
+    fn radius_targets_everything_around_the_caster() {
+        let mut tags = TagRegistry::new();
+        let invulnerable = tags.register("State.Invulnerable");
+        let mut world = World::new();
+        world.insert_resource(tags);
+        let caster = spawn_at(&mut world, Vec3::ZERO, &[]);
+        let near = spawn_at(&mut world, Vec3::Y * 2., &[]);
+        spawn_at(&mut world, Vec3::X, &[invulnerable]);
+        spawn_at(&mut world, Vec3::X * 5., &[]);
+        let mode = TargetingMode::Radius { radius: 3., filter: TargetFilter::new().blocked([invulnerable]) };
+
+        assert_eq!(resolve(&mut world, caster, mode, TargetData::None), Ok(vec![near]));
+    }
+
+    /// Rejects points above the ground
+    struct Underground;
+
+    impl TargetValidator for Underground {
+        type Param = ();
+
+        fn validate(
+            _: &SystemParamItem<Self::Param>,
+            _: Entity,
+            _: Vec3,
+            _: &TargetData,
+            positions: &[Vec3],
+        ) -> Result<(), TargetRejectedReason> {
+            if positions.iter().any(|p| p.y > 0.) { Err(TargetRejectedReason::Invalid) } else { Ok(()) }
+        }
+    }
+
+    fn meteor(validate: bool) -> (TagId, App) {
+        let mut tags = TagRegistry::new();
+        let meteor = tags.register("Ability.Meteor");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(meteor)
+            .with_targeting(TargetingMode::Point { range: 10. })
+            .with_stat_cost(StatCost::new(TestStats::Mana, 4.))
+            .with_cost_policy(CostPolicy::OnActivate)
+            .with_cooldown(5.)
+        );
+        if validate {
+            abilities.set_target_validator::<Underground>();
+        }
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &TargetRejected| e.reason);
+        record(&mut app, |e: &TargetConfirmed| e.data.point());
+        (meteor, app)
+    }
+
+    fn spawn_targeting_caster(app: &mut App, meteor: TagId) -> Entity {
+        let caster = spawn_caster(app, [meteor]);
+        app.world_mut().entity_mut(caster).insert(GlobalTransform::default());
+        caster
+    }
+
+    fn on_cooldown(app: &App, caster: Entity, ability: TagId) -> bool {
+        app.world().get::<AbilityCooldowns>(caster).unwrap().is_on_cooldown(ability)
+    }
+
+    #[test]
+    fn targets_are_paid_for_when_confirmed() {
+        let (meteor, mut app) = meteor(false);
+        let caster = spawn_targeting_caster(&mut app, meteor);
+
+        try_execute(&mut app, caster, meteor);
+        trigger(&mut app, ConfirmTarget{ entity: caster, ability: meteor, data: TargetData::Point(Vec3::X * 20.) });
+        update(&mut app);
+        assert_eq!(recorded::<TargetRejected, TargetRejectedReason>(&app), vec![TargetRejectedReason::OutOfRange]);
+        assert_eq!(mana(&app, caster), 10.);
+        assert!(!on_cooldown(&app, caster, meteor));
+
+        trigger(&mut app, ConfirmTarget{ entity: caster, ability: meteor, data: TargetData::Point(Vec3::X) });
+        update(&mut app);
+        assert_eq!(recorded::<TargetConfirmed, Option<Vec3>>(&app), vec![Some(Vec3::X)]);
+        assert_eq!(mana(&app, caster), 6.);
+        assert!(on_cooldown(&app, caster, meteor));
+    }
+
+    #[test]
+    fn canceled_targeting_costs_nothing() {
+        let (meteor, mut app) = meteor(false);
+        let caster = spawn_targeting_caster(&mut app, meteor);
+
+        try_execute(&mut app, caster, meteor);
+        assert!(app.world().get::<ActiveAbilities<TestStats>>(caster).unwrap().get_by_tag(meteor).unwrap().is_targeting());
+        trigger(&mut app, CancelTarget{ entity: caster, ability: meteor });
+        update(&mut app);
+
+        assert_eq!(running(&app, caster), 0);
+        assert_eq!(mana(&app, caster), 10.);
+        assert!(!on_cooldown(&app, caster, meteor));
+    }
+
+    #[test]
+    fn validators_choose_the_rejection_reason() {
+        let (meteor, mut app) = meteor(true);
+        let caster = spawn_targeting_caster(&mut app, meteor);
+
+        try_execute(&mut app, caster, meteor);
+        trigger(&mut app, ConfirmTarget{ entity: caster, ability: meteor, data: TargetData::Point(Vec3::Y) });
+        trigger(&mut app, ConfirmTarget{ entity: caster, ability: meteor, data: TargetData::Point(Vec3::NEG_Y) });
+
+        assert_eq!(recorded::<TargetRejected, TargetRejectedReason>(&app), vec![TargetRejectedReason::Invalid]);
+        assert_eq!(recorded::<TargetConfirmed, Option<Vec3>>(&app), vec![Some(Vec3::NEG_Y)]);
+    }
+
+    #[test]
+    fn casters_without_a_transform_are_rejected() {
+        let (meteor, mut app) = meteor(false);
+        let caster = spawn_caster(&mut app, [meteor]);
+
+        try_execute(&mut app, caster, meteor);
+        trigger(&mut app, ConfirmTarget{ entity: caster, ability: meteor, data: TargetData::Point(Vec3::X) });
+
+        assert_eq!(recorded::<TargetRejected, TargetRejectedReason>(&app), vec![TargetRejectedReason::Invalid]);
+        assert!(recorded::<TargetConfirmed, Option<Vec3>>(&app).is_empty());
+    }
+
+    #[test]
+    fn supplied_targets_are_checked_like_confirmed_ones() {
+        let (meteor, mut app) = meteor(true);
+        let caster = spawn_targeting_caster(&mut app, meteor);
+
+        for data in [TargetData::Point(Vec3::X * 1000.), TargetData::Direction(Vec3::X), TargetData::Point(Vec3::Y)] {
+            let ability = ability(&app, meteor).with_target_data(data);
+            trigger(&mut app, TryExecuteAbility { entity: caster, ability });
+            update(&mut app);
+            trigger(&mut app, CancelTarget{ entity: caster, ability: meteor });
+        }
+        assert_eq!(recorded::<TargetRejected, TargetRejectedReason>(&app), vec![
+            TargetRejectedReason::OutOfRange,
+            TargetRejectedReason::WrongKind,
+            TargetRejectedReason::Invalid,
+        ]);
+        assert!(recorded::<TargetConfirmed, Option<Vec3>>(&app).is_empty());
+        assert_eq!(mana(&app, caster), 10.);
+
+        let ability = ability(&app, meteor).with_target_data(TargetData::Point(Vec3::X));
+        trigger(&mut app, TryExecuteAbility { entity: caster, ability });
+        update(&mut app);
+        assert_eq!(recorded::<TargetConfirmed, Option<Vec3>>(&app), vec![Some(Vec3::X)]);
+        assert_eq!(mana(&app, caster), 6.);
+    }
+}
@@ -0,0 +1,103 @@
+//! Shared setup for the unit tests
+use std::{marker::PhantomData, time::Duration};
+use bevy::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use bevy_hierarchical_tags::prelude::*;
+use crate::prelude::*;
+
+
+stats!(TestStats { Mana, Health });
+
+impl std::str::FromStr for TestStats {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Mana" => Ok(Self::Mana),
+            "Health" => Ok(Self::Health),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Mana and health both start at 10
+pub(crate) fn test_stats() -> GameplayStats<TestStats> {
+    GameplayStats::<TestStats>::new(|stat| match stat {
+        TestStats::Mana => 10.,
+        TestStats::Health => 10.,
+        TestStats::None => 0.,
+    })
+}
+
+/// An app with the abilities and effects plugins and a manually advanced clock
+pub(crate) fn app(tags: TagRegistry, abilities: AbilitiesPlugin<TestStats>) -> App {
+    let mut app = App::new();
+    app.insert_resource(tags);
+    app.init_resource::<Time>();
+    app.add_plugins((GameplayEffectsPlugin::<TestStats>::default(), abilities));
+    app
+}
+
+/// Spawn an entity that can run the granted abilities and pay for them with TestStats
+pub(crate) fn spawn_caster(app: &mut App, granted: impl IntoIterator<Item = TagId>) -> Entity {
+    app.world_mut().spawn((
+        ActiveTags::default(),
+        ActiveAbilities::<TestStats>::new(),
+        GrantedAbilities::<TestStats>::innate(granted),
+        test_stats(),
+    )).id()
+}
+
+/// Run one frame with no time passing
+pub(crate) fn update(app: &mut App) {
+    tick(app, 0.);
+}
+
+/// Run one frame that is `seconds` long
+pub(crate) fn tick(app: &mut App, seconds: f32) {
+    app.world_mut().resource_mut::<Time>().advance_by(Duration::from_secs_f32(seconds));
+    app.update();
+}
+
+pub(crate) fn trigger(app: &mut App, event: impl Event) {
+    app.world_mut().trigger(event);
+    app.world_mut().flush();
+}
+
+pub(crate) fn ability(app: &App, tag: TagId) -> Ability<TestStats> {
+    Ability::from(app.world().resource::<AbilityRegistry<TestStats>>().get(&tag).unwrap())
+}
+
+pub(crate) fn try_execute(app: &mut App, entity: Entity, tag: TagId) {
+    let ability = ability(app, tag);
+    trigger(app, TryExecuteAbility { entity, ability });
+}
+
+pub(crate) fn mana(app: &App, entity: Entity) -> f32 {
+    app.world().get::<GameplayStats<TestStats>>(entity).unwrap().get(TestStats::Mana).current_value
+}
+
+pub(crate) fn has_tag(app: &App, entity: Entity, tag: TagId) -> bool {
+    let registry = app.world().resource::<TagRegistry>();
+    app.world().get::<ActiveTags>(entity).unwrap().any_match(tag, registry)
+}
+
+pub(crate) fn running(app: &App, entity: Entity) -> usize {
+    app.world().get::<ActiveAbilities<TestStats>>(entity).unwrap().len()
+}
+
+/// Values pulled out of every E that was triggered
+#[derive(Resource)]
+pub(crate) struct Recorded<E, R>(pub Vec<R>, PhantomData<fn(E)>);
+
+/// Keep a value from every E triggered from now on
+pub(crate) fn record<E: Event, R: Clone + Send + Sync + 'static>(app: &mut App, f: fn(&E) -> R) {
+    app.insert_resource(Recorded::<E, R>(Vec::new(), PhantomData));
+    app.add_observer(move |trigger: Trigger<E>, mut recorded: ResMut<Recorded<E, R>>| {
+        recorded.0.push(f(trigger.event()));
+    });
+}
+
+pub(crate) fn recorded<E: Event, R: Clone + Send + Sync + 'static>(app: &App) -> Vec<R> {
+    app.world().resource::<Recorded<E, R>>().0.clone()
+}
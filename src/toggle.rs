@@ -0,0 +1,120 @@
+use bevy::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use crate::prelude::*;
+
+
+/// Pays the per-second stat drains of running abilities, and ends any ability
+/// that can no longer afford them, e.g. a sprint when stamina runs out
+pub(crate) fn drain_stats<T: StatTrait>(
+    q: Query<(Entity, &ActiveAbilities<T>, &GameplayStats<T>)>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let dt = time.delta_secs();
+    for (entity, active, stats) in q.iter() {
+        for ability in active.iter() {
+            if ability.stat_drains.is_empty() { continue }
+            let level = ability.level();
+            let exhausted = ability.stat_drains.iter()
+                .any(|drain| stats.get(drain.stat).current_value < drain.amount.at(level) * dt);
+            if exhausted {
+                commands.trigger(EndAbility{
+                    entity,
+                    ability: ability.clone(),
+                    outcome: AbilityOutcome::Interrupted,
+                });
+                continue;
+            }
+            for drain in ability.stat_drains.iter() {
+                commands.trigger(AddEffect(AddEffectData::<T>::new(
+                    entity,
+                    GameplayEffect::new(
+                        None,
+                        drain.stat,
+                        EffectMagnitude::Fixed(-drain.amount.at(level) * dt),
+                        EffectCalculation::Additive,
+                        EffectDuration::Immediate,
+                    ),
+                    Some(entity),
+                )));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    fn sprint(tags: &mut TagRegistry) -> (TagId, TagId, AbilitiesPlugin<TestStats>) {
+        let movement = tags.register("Ability.Movement");
+        let sprint = tags.register("Ability.Movement.Sprint");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(sprint)
+            .with_activation_policy(ActivationPolicy::Toggle)
+            .with_stat_cost(StatCost::new(TestStats::Mana, 2.))
+            .with_cost_policy(CostPolicy::OnActivate)
+        );
+        (movement, sprint, abilities)
+    }
+
+    #[test]
+    fn toggles_turn_off_on_the_next_activation_without_paying() {
+        let mut tags = TagRegistry::new();
+        let (_, sprint, abilities) = sprint(&mut tags);
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &EndAbility<TestStats>| e.outcome);
+        let caster = spawn_caster(&mut app, [sprint]);
+
+        try_execute(&mut app, caster, sprint);
+        update(&mut app);
+        assert_eq!(running(&app, caster), 1);
+
+        try_execute(&mut app, caster, sprint);
+        update(&mut app);
+        assert_eq!(running(&app, caster), 0);
+        assert_eq!(mana(&app, caster), 8.);
+        assert_eq!(recorded::<EndAbility<TestStats>, AbilityOutcome>(&app), vec![AbilityOutcome::Succeeded]);
+    }
+
+    #[test]
+    fn toggles_turn_off_through_their_parent_tag() {
+        let mut tags = TagRegistry::new();
+        let (movement, sprint, abilities) = sprint(&mut tags);
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| e.ability);
+        let caster = spawn_caster(&mut app, [sprint]);
+
+        trigger(&mut app, TryActivateAbilityByParentTag{ entity: caster, parent: movement });
+        assert_eq!(running(&app, caster), 1);
+        trigger(&mut app, TryActivateAbilityByParentTag{ entity: caster, parent: movement });
+        assert_eq!(running(&app, caster), 0);
+        assert!(recorded::<AbilityActivationFailed<TestStats>, TagId>(&app).is_empty());
+    }
+
+    #[test]
+    fn drains_end_the_ability_when_the_stat_runs_out() {
+        let mut tags = TagRegistry::new();
+        let shield = tags.register("Ability.Shield");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(shield)
+            .with_activation_policy(ActivationPolicy::Toggle)
+            .with_stat_drain(StatCost::new(TestStats::Mana, 4.))
+        );
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &EndAbility<TestStats>| e.outcome);
+        let caster = spawn_caster(&mut app, [shield]);
+
+        try_execute(&mut app, caster, shield);
+        tick(&mut app, 1.);
+        tick(&mut app, 1.);
+        assert_eq!(mana(&app, caster), 2.);
+        assert_eq!(running(&app, caster), 1);
+
+        tick(&mut app, 1.);
+        assert_eq!(running(&app, caster), 0);
+        assert_eq!(recorded::<EndAbility<TestStats>, AbilityOutcome>(&app), vec![AbilityOutcome::Interrupted]);
+    }
+}
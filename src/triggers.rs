@@ -0,0 +1,230 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_hierarchical_tags::prelude::*;
+use bevy_gameplay_effects::prelude::*;
+use crate::{tags::tag_matches, prelude::*};
+
+
+/// Makes an ability activate itself when something happens to the entity that was granted it
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AbilityTrigger {
+    /// The tag (or one of its children) appears in ActiveTags
+    TagAdded(TagId),
+    /// The tag (and all of its children) disappear from ActiveTags
+    TagRemoved(TagId),
+    /// A SendGameplayEvent with this tag (or one of its children) is sent to the entity
+    GameplayEvent(TagId),
+}
+
+/// The payload of a SendGameplayEvent.
+/// Abilities activated by the event can read it from Ability::trigger_data.
+#[derive(Clone)]
+pub struct GameplayEventData {
+    pub tag: TagId,
+    /// Whoever caused the event, e.g. the attacker whose hit was parried
+    pub instigator: Option<Entity>,
+    pub target: Option<Entity>,
+    pub magnitude: f32,
+}
+
+impl GameplayEventData {
+    pub fn new(tag: TagId) -> Self {
+        Self { tag, instigator: None, target: None, magnitude: 0. }
+    }
+
+    pub fn with_instigator(mut self, instigator: Entity) -> Self {
+        self.instigator = Some(instigator);
+        self
+    }
+
+    pub fn with_target(mut self, target: Entity) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn with_magnitude(mut self, magnitude: f32) -> Self {
+        self.magnitude = magnitude;
+        self
+    }
+}
+
+/// Remembers whether each trigger tag was present last frame, keyed by (ability, tag).
+/// This is added automatically with ActiveAbilities.
+#[derive(Component, Default)]
+pub struct AbilityTriggerState(HashMap<(TagId, TagId), bool>);
+
+/// Activates granted abilities whose trigger tags were added or removed since last frame
+pub(crate) fn check_ability_triggers<T: StatTrait>(
+    mut q: Query<(Entity, &ActiveTags, &GrantedAbilities<T>, &mut AbilityTriggerState)>,
+    registry: Res<AbilityRegistry<T>>,
+    tag_registry: Res<TagRegistry>,
+    mut commands: Commands,
+) {
+    for (entity, tags, granted, mut state) in q.iter_mut() {
+        // Forget abilities that were revoked
+        if !state.0.is_empty() {
+            state.0.retain(|(ability, _), _| granted.contains(*ability));
+        }
+        for (ability, _) in granted.iter() {
+            let Some(definition) = registry.get(&ability) else { continue };
+            for trigger in definition.triggers.iter() {
+                let (tag, on_added) = match trigger {
+                    AbilityTrigger::TagAdded(tag) => (*tag, true),
+                    AbilityTrigger::TagRemoved(tag) => (*tag, false),
+                    AbilityTrigger::GameplayEvent(_) => continue,
+                };
+                let present = tags.any_match(tag, &tag_registry);
+                // The first frame only records the state, so tags that were already
+                // present when the ability was granted don't trigger it
+                let Some(was_present) = state.0.insert((ability, tag), present) else { continue };
+                if present != was_present && present == on_added {
+                    let Some(mut ability) = granted.ability(ability, &registry) else { continue };
+                    ability.triggered = true;
+                    commands.trigger(TryExecuteAbility{ entity, ability });
+                }
+            }
+        }
+    }
+}
+
+/// Activates granted abilities listening for the gameplay event, passing along its payload
+pub(crate) fn on_gameplay_event<T: StatTrait>(
+    trigger: Trigger<SendGameplayEvent>,
+    granted: Query<&GrantedAbilities<T>>,
+    registry: Res<AbilityRegistry<T>>,
+    tag_registry: Res<TagRegistry>,
+    mut commands: Commands,
+) {
+    let SendGameplayEvent{ entity, data } = trigger.event();
+    let Ok(granted) = granted.get(*entity) else { return };
+    for (tag, _) in granted.iter() {
+        let Some(definition) = registry.get(&tag) else { continue };
+        let listening = definition.triggers.iter().any(|t| match t {
+            AbilityTrigger::GameplayEvent(event) => tag_matches(data.tag, *event, &tag_registry),
+            _ => false,
+        });
+        if !listening { continue }
+        let Some(mut ability) = granted.ability(tag, &registry) else { continue };
+        if let Some(target) = data.target {
+            ability.target_data = TargetData::Entity(target);
+        }
+        ability.trigger_data = Some(data.clone());
+        ability.triggered = true;
+        commands.trigger(TryExecuteAbility{ entity: *entity, ability });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_hierarchical_tags::prelude::*;
+    use crate::{prelude::*, testing::*};
+
+    #[test]
+    fn tag_triggers_fire_on_changes_only() {
+        let mut tags = TagRegistry::new();
+        let enrage = tags.register("Ability.Enrage");
+        let recover = tags.register("Ability.Recover");
+        let wounded = tags.register("Character.State.Wounded");
+        let bleeding = tags.register("Character.State.Wounded.Bleeding");
+        let stunned = tags.register("Character.State.Stunned");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(enrage).triggered_by(AbilityTrigger::TagAdded(wounded)));
+        abilities.register(AbilityDefinition::new(recover).triggered_by(AbilityTrigger::TagRemoved(stunned)));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &ExecuteAbility<TestStats>| e.ability.tags.ability);
+        let caster = spawn_caster(&mut app, [enrage, recover]);
+        // Already present when granted, so removing it is the only change that counts
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(stunned);
+
+        update(&mut app);
+        assert!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).is_empty());
+
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(bleeding);
+        update(&mut app);
+        update(&mut app);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![enrage]);
+
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(stunned);
+        update(&mut app);
+        assert_eq!(recorded::<ExecuteAbility<TestStats>, TagId>(&app), vec![enrage, recover]);
+    }
+
+    #[test]
+    fn gameplay_events_pass_their_payload_to_listening_abilities() {
+        let mut tags = TagRegistry::new();
+        let riposte = tags.register("Ability.Riposte");
+        let dodge = tags.register("Ability.Dodge");
+        let parried = tags.register("Event.Combat.Parried");
+        let perfect = tags.register("Event.Combat.Parried.Perfect");
+        let hit = tags.register("Event.Combat.Hit");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(riposte).triggered_by(AbilityTrigger::GameplayEvent(parried)));
+        abilities.register(AbilityDefinition::new(dodge).triggered_by(AbilityTrigger::GameplayEvent(hit)));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &ExecuteAbility<TestStats>| {
+            let data = e.ability.trigger_data.as_ref().unwrap();
+            (e.ability.tags.ability, e.ability.target_data.clone(), data.tag, data.magnitude)
+        });
+        let caster = spawn_caster(&mut app, [riposte, dodge]);
+        let attacker = app.world_mut().spawn_empty().id();
+
+        let data = GameplayEventData::new(perfect).with_target(attacker).with_magnitude(2.);
+        trigger(&mut app, SendGameplayEvent{ entity: caster, data });
+
+        let executed = recorded::<ExecuteAbility<TestStats>, (TagId, TargetData, TagId, f32)>(&app);
+        assert_eq!(executed.len(), 1);
+        let (ability, target_data, tag, magnitude) = &executed[0];
+        assert_eq!((*ability, *tag, *magnitude), (riposte, perfect, 2.));
+        assert!(matches!(target_data, TargetData::Entity(e) if *e == attacker));
+    }
+
+    #[test]
+    fn revoked_abilities_are_forgotten() {
+        let mut tags = TagRegistry::new();
+        let enrage = tags.register("Ability.Enrage");
+        let wounded = tags.register("Character.State.Wounded");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(enrage).triggered_by(AbilityTrigger::TagAdded(wounded)));
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &ExecuteAbility<TestStats>| e.ability.tags.ability);
+        let caster = spawn_caster(&mut app, [enrage]);
+
+        update(&mut app);
+        app.world_mut().get_mut::<GrantedAbilities<TestStats>>(caster).unwrap().revoke(enrage);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(wounded);
+        update(&mut app);
+        // Granting again starts from the current tags rather than last frame's
+        app.world_mut().get_mut::<GrantedAbilities<TestStats>>(caster).unwrap().grant(enrage, 1);
+        update(&mut app);
+        assert!(recorded::<ExecuteAbility<TestStats>, TagId>(&app).is_empty());
+    }
+
+    #[test]
+    fn triggered_activations_are_never_buffered() {
+        let mut tags = TagRegistry::new();
+        let enrage = tags.register("Ability.Enrage");
+        let wounded = tags.register("Character.State.Wounded");
+        let stunned = tags.register("Character.State.Stunned");
+        let mut abilities = AbilitiesPlugin::<TestStats>::new();
+        abilities.register(AbilityDefinition::new(enrage)
+            .triggered_by(AbilityTrigger::TagAdded(wounded))
+            .blocked_by([stunned])
+        );
+        let mut app = app(tags, abilities);
+        record(&mut app, |e: &AbilityActivationBuffered| e.ability);
+        record(&mut app, |e: &AbilityActivationFailed<TestStats>| e.ability);
+        let caster = spawn_caster(&mut app, [enrage]);
+        app.world_mut().entity_mut(caster).insert(AbilityInputBuffer::new(1.));
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(stunned);
+        update(&mut app);
+
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().push(wounded);
+        update(&mut app);
+        app.world_mut().get_mut::<ActiveTags>(caster).unwrap().remove(stunned);
+        update(&mut app);
+
+        assert!(recorded::<AbilityActivationBuffered, TagId>(&app).is_empty());
+        assert_eq!(recorded::<AbilityActivationFailed<TestStats>, TagId>(&app), vec![enrage]);
+        assert_eq!(running(&app, caster), 0);
+    }
+}